@@ -22,6 +22,7 @@ let
     fileset = lib.fileset.unions [
       (craneLib.fileset.commonCargoSources ../.)
       ../src/favicon.svg
+      ../src/openapi.json
       ../templates
     ];
   };
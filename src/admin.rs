@@ -0,0 +1,306 @@
+use std::{
+    pin::Pin,
+    sync::atomic::Ordering,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use askama::Template;
+use axum::{
+    Extension, Router,
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, Uri, header},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+};
+use bytes::Bytes;
+use http_body::{Body as HttpBody, Frame};
+use tokio::{sync::mpsc, time::Instant};
+use uuid::Uuid;
+
+use crate::{
+    config::Role,
+    session::AppState,
+    templates::{
+        AdminIndexTemplate, AdminPausedTemplate, AdminSessionRow, AdminSnapshotTemplate,
+        KilledTemplate,
+    },
+    utils::bps_to_string,
+};
+
+static UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Live updates stop after this long, or when the server shuts down, so that an
+/// open dashboard does not grow without bounds.
+static STREAM_DURATION: Duration = Duration::from_secs(600);
+
+/// Routes behind [`AuthLayer`](crate::auth::AuthLayer), which adds the
+/// [`Role`] of the client.
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(dashboard))
+        .route("/sessions/{id}/kill", post(kill))
+}
+
+struct ChannelBody(mpsc::Receiver<Bytes>);
+
+impl HttpBody for ChannelBody {
+    type Data = Bytes;
+
+    type Error = color_eyre::Report;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.0
+            .poll_recv(cx)
+            .map(|bytes| bytes.map(|bytes| Ok(Frame::data(bytes))))
+    }
+}
+
+fn elapsed_to_string(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    if seconds >= 3_600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3_600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+fn snapshot(state: &AppState, can_kill: bool) -> AdminSnapshotTemplate {
+    let sessions: Vec<_> = state
+        .overview()
+        .into_iter()
+        .map(|session| AdminSessionRow {
+            id: session.id,
+            short_id: session.id.simple().to_string()[..8].to_owned(),
+            state: session.state,
+            client: session.client,
+            elapsed: elapsed_to_string(session.elapsed),
+            throughput: session
+                .download_bps
+                .map_or_else(|| "--".into(), bps_to_string),
+            counter: session.counter,
+        })
+        .collect();
+    AdminSnapshotTemplate {
+        open_sessions: sessions.len(),
+        running_tests: sessions
+            .iter()
+            .filter(|session| session.state == "downloading")
+            .count(),
+        sessions,
+        max_sessions: state.max_sessions,
+        stored_results: state.results.len(),
+        sessions_total: state.counters.sessions.load(Ordering::Relaxed),
+        downloads: state.counters.downloads.load(Ordering::Relaxed),
+        uploads: state.counters.uploads.load(Ordering::Relaxed),
+        killed: state.counters.killed.load(Ordering::Relaxed),
+        draining: state.draining.load(Ordering::Relaxed),
+        can_kill,
+    }
+}
+
+/// Streams a snapshot of every session each second; older snapshots are
+/// hidden by CSS.
+async fn dashboard(State(state): State<AppState>, Extension(role): Extension<Role>) -> Response {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        if tx
+            .send(Bytes::from(AdminIndexTemplate.render().unwrap()))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let end = Instant::now() + STREAM_DURATION;
+        let mut interval = tokio::time::interval(UPDATE_INTERVAL);
+        loop {
+            if interval.tick().await >= end || state.draining.load(Ordering::Acquire) {
+                let _ = tx
+                    .send(Bytes::from(AdminPausedTemplate.render().unwrap()))
+                    .await;
+                break;
+            }
+            let html = snapshot(&state, role >= Role::Operator).render().unwrap();
+            if tx.send(Bytes::from(html)).await.is_err() {
+                break;
+            }
+        }
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        Body::new(ChannelBody(rx)),
+    )
+        .into_response()
+}
+
+/// Whether a form post comes from this server's own pages, since browsers send
+/// Basic credentials along with cross-site ones.
+fn same_origin(headers: &HeaderMap) -> bool {
+    if let Some(site) = headers.get("Sec-Fetch-Site") {
+        return site == "same-origin" || site == "none";
+    }
+    // Browsers without Fetch metadata still send the origin or the referring
+    // page, which must be on the requested host.
+    let Some(host) = headers.get(header::HOST) else {
+        return false;
+    };
+    headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+        .and_then(|source| source.to_str().ok()?.parse::<Uri>().ok())
+        .is_some_and(|source| {
+            source.authority().is_some_and(|authority| {
+                authority
+                    .as_str()
+                    .as_bytes()
+                    .eq_ignore_ascii_case(host.as_bytes())
+            })
+        })
+}
+
+async fn kill(State(state): State<AppState>, Path(id): Path<Uuid>, headers: HeaderMap) -> Response {
+    if !same_origin(&headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    state.kill(
+        id,
+        &Bytes::from(KilledTemplate.render().unwrap()),
+        "This session was closed by the server operator.",
+    );
+    Redirect::to("/admin").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Header names and values.
+    type Headers = &'static [(&'static str, &'static str)];
+
+    fn headers(pairs: Headers) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn same_origin() {
+        let cases: &[(&str, Headers, bool)] = &[
+            ("same origin", &[("sec-fetch-site", "same-origin")], true),
+            ("typed address", &[("sec-fetch-site", "none")], true),
+            ("cross site", &[("sec-fetch-site", "cross-site")], false),
+            (
+                "cross site with a matching origin",
+                &[
+                    ("sec-fetch-site", "same-site"),
+                    ("host", "speedtest.example"),
+                    ("origin", "https://speedtest.example"),
+                ],
+                false,
+            ),
+            (
+                "matching origin",
+                &[
+                    ("host", "speedtest.example:8080"),
+                    ("origin", "http://speedtest.example:8080"),
+                ],
+                true,
+            ),
+            (
+                "matching referer",
+                &[
+                    ("host", "speedtest.example"),
+                    ("referer", "https://Speedtest.example/admin"),
+                ],
+                true,
+            ),
+            (
+                "other origin",
+                &[
+                    ("host", "speedtest.example"),
+                    ("origin", "https://evil.example"),
+                ],
+                false,
+            ),
+            (
+                "other port",
+                &[
+                    ("host", "speedtest.example"),
+                    ("origin", "https://speedtest.example:8443"),
+                ],
+                false,
+            ),
+            (
+                "other origin with a matching referer",
+                &[
+                    ("host", "speedtest.example"),
+                    ("origin", "https://evil.example"),
+                    ("referer", "https://speedtest.example/admin"),
+                ],
+                false,
+            ),
+            (
+                "opaque origin",
+                &[("host", "speedtest.example"), ("origin", "null")],
+                false,
+            ),
+            (
+                "neither origin nor referer",
+                &[("host", "speedtest.example")],
+                false,
+            ),
+            ("no host", &[("origin", "https://speedtest.example")], false),
+        ];
+        for &(name, pairs, expected) in cases {
+            assert_eq!(super::same_origin(&headers(pairs)), expected, "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn cross_site_kill() {
+        let state = AppState::for_tests();
+        let id = Uuid::new_v4();
+        let (_sender, _body) = state.insert(id, Ipv4Addr::LOCALHOST.into(), false);
+        let app = router().with_state(state.clone());
+        let kill = async |origin: &str| {
+            app.clone()
+                .oneshot(
+                    Request::builder()
+                        .method("POST")
+                        .uri(format!("/sessions/{id}/kill"))
+                        .header(header::HOST, "speedtest.example")
+                        .header(header::ORIGIN, origin)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+                .status()
+        };
+        assert_eq!(kill("https://evil.example").await, StatusCode::FORBIDDEN);
+        assert!(state.snapshot(id).is_some());
+        assert_eq!(
+            kill("https://speedtest.example").await,
+            StatusCode::SEE_OTHER
+        );
+        assert!(state.snapshot(id).is_none());
+    }
+}
@@ -0,0 +1,54 @@
+use axum::{
+    extract::{Path, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Json},
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::session::{AppState, SessionSnapshot, TestResult};
+
+#[derive(Serialize)]
+pub(crate) struct SessionResponse {
+    id: Uuid,
+    #[serde(flatten)]
+    snapshot: SessionSnapshot,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ResultResponse {
+    id: Uuid,
+    #[serde(flatten)]
+    result: TestResult,
+}
+
+pub(crate) async fn session(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> impl IntoResponse {
+    match state.snapshot(id) {
+        Some(snapshot) => Json(SessionResponse { id, snapshot }).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub(crate) async fn result(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> impl IntoResponse {
+    match state.results.get(&id) {
+        Some(result) => Json(ResultResponse {
+            id,
+            result: result.clone(),
+        })
+        .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub(crate) async fn openapi() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        include_bytes!("./openapi.json"),
+    )
+}
@@ -0,0 +1,545 @@
+use std::{
+    convert::Infallible,
+    io::{self, BufRead},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use argon2::{
+    Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString,
+};
+use axum::{
+    extract::Request,
+    http::{HeaderValue, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use color_eyre::eyre::{Context as _, eyre};
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+use tokio::sync::Semaphore;
+use tower::{Layer, Service};
+use tracing::debug;
+
+use crate::{
+    config::{AuthConfig, Role},
+    connection::TlsInfo,
+};
+
+/// Each Argon2 verification takes about 19 MiB and a blocking thread.
+static MAX_CONCURRENT_VERIFICATIONS: usize = 4;
+
+pub(crate) struct Authenticator {
+    protect_api: bool,
+    tokens: Vec<(String, Role)>,
+    users: Vec<(String, String, Role)>,
+    /// Verified for unknown usernames, so that timing does not reveal which
+    /// users exist.
+    dummy_hash: String,
+    verifications: Arc<Semaphore>,
+    certificates: Vec<(String, Role)>,
+}
+
+impl Authenticator {
+    pub(crate) fn new(config: AuthConfig) -> color_eyre::Result<Self> {
+        for user in &config.users {
+            PasswordHash::new(&user.password_hash)
+                .map_err(|error| eyre!("{error}"))
+                .wrap_err_with(|| format!("invalid password hash of user {:?}", user.username))?;
+        }
+        // Unknown usernames must take as long as known ones, so the dummy hash
+        // has the algorithm and parameters of a configured user.
+        let dummy_hash = match config.users.first() {
+            Some(user) => {
+                let mut password = [0u8; 16];
+                rand::rng().fill_bytes(&mut password);
+                let like =
+                    PasswordHash::new(&user.password_hash).map_err(|error| eyre!("{error}"))?;
+                hash(&password, Some(&like)).wrap_err_with(|| {
+                    format!("unsupported password hash of user {:?}", user.username)
+                })?
+            }
+            None => String::new(),
+        };
+        Ok(Authenticator {
+            protect_api: config.protect_api,
+            tokens: config
+                .tokens
+                .into_iter()
+                .map(|token| (token.token, token.role))
+                .collect(),
+            users: config
+                .users
+                .into_iter()
+                .map(|user| (user.username, user.password_hash, user.role))
+                .collect(),
+            dummy_hash,
+            verifications: Arc::new(Semaphore::new(MAX_CONCURRENT_VERIFICATIONS)),
+            certificates: config
+                .certificates
+                .into_iter()
+                .map(|certificate| (certificate.common_name, certificate.role))
+                .collect(),
+        })
+    }
+
+    /// Role needed for the request, or `None` for public routes. Reading is
+    /// read-only, anything else takes an operator.
+    fn required_role(&self, method: &Method, path: &str) -> Option<Role> {
+        let protected = path == "/admin"
+            || path.starts_with("/admin/")
+            || (self.protect_api
+                && (path.starts_with("/api/v1/sessions/") || path.starts_with("/api/v1/results/")));
+        if !protected {
+            None
+        } else if method == Method::GET || method == Method::HEAD {
+            Some(Role::ReadOnly)
+        } else {
+            Some(Role::Operator)
+        }
+    }
+
+    /// Highest role granted by the client certificate or the `Authorization`
+    /// header.
+    async fn authenticate(
+        &self,
+        authorization: Option<HeaderValue>,
+        client_name: Option<Arc<str>>,
+    ) -> Option<Role> {
+        let certificate_role = client_name.and_then(|client_name| {
+            self.certificates
+                .iter()
+                .find(|(common_name, _)| **common_name == *client_name)
+                .map(|&(_, role)| role)
+        });
+        let header_role = match authorization
+            .as_ref()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+        {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => {
+                self.token_role(token.trim())
+            }
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("Basic") => {
+                self.user_role(credentials.trim()).await
+            }
+            _ => None,
+        };
+        certificate_role.max(header_role)
+    }
+
+    fn token_role(&self, token: &str) -> Option<Role> {
+        // Every token is compared, so that timing does not reveal which matched.
+        self.tokens.iter().fold(None, |matched, (expected, role)| {
+            if bool::from(token.as_bytes().ct_eq(expected.as_bytes())) {
+                Some(*role)
+            } else {
+                matched
+            }
+        })
+    }
+
+    async fn user_role(&self, credentials: &str) -> Option<Role> {
+        let credentials = BASE64_STANDARD.decode(credentials).ok()?;
+        let credentials = String::from_utf8(credentials).ok()?;
+        let (username, password) = credentials.split_once(':')?;
+        let user = self.users.iter().find(|(name, ..)| name == username);
+        let password_hash = user.map_or(&self.dummy_hash, |(_, password_hash, _)| password_hash);
+        let (password, password_hash) = (password.to_owned(), password_hash.clone());
+        let permit = Arc::clone(&self.verifications).acquire_owned().await.ok()?;
+        // Argon2 is deliberately slow, so keep it off the async workers. The
+        // permit is held until it finishes, even if the client goes away.
+        let verified = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            PasswordHash::new(&password_hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+        })
+        .await
+        .unwrap_or(false);
+        user.filter(|_| verified).map(|&(.., role)| role)
+    }
+
+    fn unauthorized(&self) -> Response {
+        let mut response = (StatusCode::UNAUTHORIZED, "Unauthorized.\n").into_response();
+        if !self.users.is_empty() {
+            response.headers_mut().append(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"admin\", charset=\"UTF-8\""),
+            );
+        }
+        if !self.tokens.is_empty() {
+            response.headers_mut().append(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Bearer realm=\"admin\""),
+            );
+        }
+        response
+    }
+}
+
+/// Rejects requests to non-public routes without a sufficient role, and adds
+/// the [`Role`] of the client to the others.
+#[derive(Clone)]
+pub(crate) struct AuthLayer(Arc<Authenticator>);
+
+impl AuthLayer {
+    pub(crate) fn new(authenticator: Authenticator) -> Self {
+        AuthLayer(Arc::new(authenticator))
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthService {
+            inner,
+            authenticator: Arc::clone(&self.0),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct AuthService<S> {
+    inner: S,
+    authenticator: Arc<Authenticator>,
+}
+
+impl<S> Service<Request> for AuthService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+
+    type Error = Infallible;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let Some(required_role) = self
+            .authenticator
+            .required_role(request.method(), request.uri().path())
+        else {
+            return Box::pin(self.inner.call(request));
+        };
+        // The ready service is taken, leaving a clone for the next call.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let authenticator = Arc::clone(&self.authenticator);
+        let authorization = request.headers().get(header::AUTHORIZATION).cloned();
+        let client_name = request
+            .extensions()
+            .get::<TlsInfo>()
+            .and_then(|tls| tls.client_name.clone());
+        Box::pin(async move {
+            match authenticator.authenticate(authorization, client_name).await {
+                Some(role) if role >= required_role => {
+                    request.extensions_mut().insert(role);
+                    inner.call(request).await
+                }
+                Some(role) => {
+                    debug!(?role, ?required_role, "Insufficient role.");
+                    Ok((StatusCode::FORBIDDEN, "Forbidden.\n").into_response())
+                }
+                None => Ok(authenticator.unauthorized()),
+            }
+        })
+    }
+}
+
+/// Reads a password from standard input and prints its Argon2 hash, for the
+/// `password_hash` of `[[auth.users]]`.
+pub(crate) fn hash_password() -> color_eyre::Result<()> {
+    let mut password = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .wrap_err("failed to read password")?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(eyre!("password must not be empty"));
+    }
+    println!("{}", hash(password.as_bytes(), None)?);
+    Ok(())
+}
+
+/// Hashes `password` with a random salt, and the algorithm, version and
+/// parameters of `like` or the defaults.
+fn hash(password: &[u8], like: Option<&PasswordHash>) -> color_eyre::Result<String> {
+    let mut salt = [0u8; 16];
+    rand::rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).map_err(|error| eyre!("invalid salt: {error}"))?;
+    let hash = match like {
+        Some(like) => Params::try_from(like).and_then(|params| {
+            Argon2::default().hash_password_customized(
+                password,
+                Some(like.algorithm),
+                like.version,
+                params,
+                &salt,
+            )
+        }),
+        None => Argon2::default().hash_password(password, &salt),
+    }
+    .map_err(|error| eyre!("failed to hash password: {error}"))?;
+    Ok(hash.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{Algorithm, Version};
+    use axum::{Extension, Router, body::Body, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::{CertificateConfig, TokenConfig, UserConfig};
+
+    /// Cheap parameters, which the dummy hash must take over.
+    fn test_hash(password: &str) -> String {
+        let salt = SaltString::encode_b64(b"test salt").unwrap();
+        Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(8, 1, 1, None).unwrap(),
+        )
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+    }
+
+    fn authenticator(protect_api: bool) -> Authenticator {
+        Authenticator::new(AuthConfig {
+            protect_api,
+            tokens: vec![
+                TokenConfig {
+                    token: "reader-token".into(),
+                    role: Role::ReadOnly,
+                },
+                TokenConfig {
+                    token: "operator-token".into(),
+                    role: Role::Operator,
+                },
+            ],
+            users: vec![
+                UserConfig {
+                    username: "reader".into(),
+                    password_hash: test_hash("reader password"),
+                    role: Role::ReadOnly,
+                },
+                UserConfig {
+                    username: "operator".into(),
+                    password_hash: test_hash("operator password"),
+                    role: Role::Operator,
+                },
+            ],
+            certificates: vec![CertificateConfig {
+                common_name: "ops".into(),
+                role: Role::Operator,
+            }],
+        })
+        .unwrap()
+    }
+
+    fn basic(credentials: &str) -> Option<HeaderValue> {
+        Some(
+            format!("Basic {}", BASE64_STANDARD.encode(credentials))
+                .parse()
+                .unwrap(),
+        )
+    }
+
+    fn bearer(token: &str) -> Option<HeaderValue> {
+        Some(format!("Bearer {token}").parse().unwrap())
+    }
+
+    #[test]
+    fn required_role() {
+        let cases = [
+            (false, "GET", "/", None),
+            (false, "GET", "/administrator", None),
+            (false, "GET", "/admin", Some(Role::ReadOnly)),
+            (false, "HEAD", "/admin/", Some(Role::ReadOnly)),
+            (
+                false,
+                "POST",
+                "/admin/sessions/1/kill",
+                Some(Role::Operator),
+            ),
+            (false, "GET", "/api/v1/sessions/1", None),
+            (false, "GET", "/api/v1/openapi.json", None),
+            (true, "GET", "/api/v1/sessions/1", Some(Role::ReadOnly)),
+            (true, "GET", "/api/v1/results/1", Some(Role::ReadOnly)),
+            (true, "GET", "/api/v1/openapi.json", None),
+            (true, "POST", "/upload", None),
+        ];
+        let authenticators = [authenticator(false), authenticator(true)];
+        for (protect_api, method, path, expected) in cases {
+            assert_eq!(
+                authenticators[usize::from(protect_api)]
+                    .required_role(&method.parse().unwrap(), path),
+                expected,
+                "{method} {path} with protect_api = {protect_api}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn roles() {
+        let authenticator = authenticator(false);
+        let cases = [
+            ("no credentials", None, None, None),
+            (
+                "read-only token",
+                bearer("reader-token"),
+                None,
+                Some(Role::ReadOnly),
+            ),
+            (
+                "operator token",
+                bearer("operator-token"),
+                None,
+                Some(Role::Operator),
+            ),
+            (
+                "bearer scheme case",
+                Some(HeaderValue::from_static("bearer operator-token")),
+                None,
+                Some(Role::Operator),
+            ),
+            ("unknown token", bearer("reader"), None, None),
+            ("token prefix", bearer("reader-toke"), None, None),
+            (
+                "read-only user",
+                basic("reader:reader password"),
+                None,
+                Some(Role::ReadOnly),
+            ),
+            (
+                "operator user",
+                basic("operator:operator password"),
+                None,
+                Some(Role::Operator),
+            ),
+            (
+                "wrong password",
+                basic("operator:reader password"),
+                None,
+                None,
+            ),
+            (
+                "unknown user",
+                basic("nobody:operator password"),
+                None,
+                None,
+            ),
+            ("no password", basic("operator"), None, None),
+            (
+                "invalid base64",
+                Some(HeaderValue::from_static("Basic !!")),
+                None,
+                None,
+            ),
+            (
+                "unknown scheme",
+                Some(HeaderValue::from_static("Digest operator-token")),
+                None,
+                None,
+            ),
+            ("certificate", None, Some("ops"), Some(Role::Operator)),
+            ("unknown certificate", None, Some("dev"), None),
+            (
+                "certificate over token",
+                bearer("reader-token"),
+                Some("ops"),
+                Some(Role::Operator),
+            ),
+        ];
+        for (name, authorization, client_name, expected) in cases {
+            assert_eq!(
+                authenticator
+                    .authenticate(authorization, client_name.map(Arc::from))
+                    .await,
+                expected,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn dummy_hash_matches_users() {
+        let authenticator = authenticator(false);
+        let dummy_hash = PasswordHash::new(&authenticator.dummy_hash).unwrap();
+        let user_hash = test_hash("reader password");
+        let user_hash = PasswordHash::new(&user_hash).unwrap();
+        assert_eq!(dummy_hash.algorithm, user_hash.algorithm);
+        assert_eq!(dummy_hash.version, user_hash.version);
+        assert_eq!(
+            Params::try_from(&dummy_hash).unwrap(),
+            Params::try_from(&user_hash).unwrap()
+        );
+        assert_ne!(dummy_hash.salt, user_hash.salt);
+    }
+
+    #[tokio::test]
+    async fn responses() {
+        let app = Router::new()
+            .route(
+                "/admin",
+                get(async |Extension(role): Extension<Role>| format!("{role:?}"))
+                    .post(async || "posted"),
+            )
+            .layer(AuthLayer::new(authenticator(false)));
+        let response = |method: &str, authorization: Option<HeaderValue>| {
+            let mut request = Request::builder().method(method).uri("/admin");
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let unauthorized = response("GET", None).await.unwrap();
+        assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+        let challenges: Vec<_> = unauthorized
+            .headers()
+            .get_all(header::WWW_AUTHENTICATE)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            challenges,
+            [
+                "Basic realm=\"admin\", charset=\"UTF-8\"",
+                "Bearer realm=\"admin\""
+            ]
+        );
+
+        let unknown_user = response("GET", basic("nobody:reader password"))
+            .await
+            .unwrap();
+        assert_eq!(unknown_user.status(), StatusCode::UNAUTHORIZED);
+
+        let read_only = response("GET", bearer("reader-token")).await.unwrap();
+        assert_eq!(read_only.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(read_only.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "ReadOnly");
+
+        let forbidden = response("POST", basic("reader:reader password"))
+            .await
+            .unwrap();
+        assert_eq!(forbidden.status(), StatusCode::FORBIDDEN);
+
+        let operator = response("POST", bearer("operator-token")).await.unwrap();
+        assert_eq!(operator.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,212 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use askama::Template;
+use axum::{
+    Extension,
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use bytes::Bytes;
+use tokio::time::sleep;
+use tracing::{Instrument, info};
+use uuid::Uuid;
+
+use crate::{
+    connection::ConnectionWrites,
+    download::{DownloadBody, DownloadFormat},
+    routes::{DownloadQuery, StartQuery},
+    session::{AppState, SessionSnapshot},
+    templates::{BasicDownloadHeaderTemplate, BasicIndexTemplate, BasicResultsTemplate},
+    utils::{
+        bps_to_string, bytes_to_string, client_ip, has_proxy_hints, measurement_headers,
+        seconds_to_string,
+    },
+};
+
+static TEXT_BROWSER_USER_AGENTS: [&str; 5] = ["Lynx/", "w3m/", "Links ", "ELinks", "Emacs-w3m/"];
+
+pub(crate) fn is_text_browser(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .is_some_and(|user_agent| {
+            TEXT_BROWSER_USER_AGENTS
+                .iter()
+                .any(|prefix| user_agent.starts_with(prefix))
+        })
+}
+
+pub(crate) async fn index(State(state): State<AppState>) -> impl IntoResponse {
+    Html(
+        BasicIndexTemplate {
+            profiles: &state.profiles,
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+pub(crate) async fn start(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    writes: Option<Extension<ConnectionWrites>>,
+    Query(StartQuery { profile, .. }): Query<StartQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(profile) = state.profile(profile.as_deref()) else {
+        return Redirect::to("/basic").into_response();
+    };
+    if state.at_session_limit() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many tests are running, please try again later.\n",
+        )
+            .into_response();
+    }
+    let id = Uuid::new_v4();
+    let addr = client_ip(&headers, addr);
+    state.insert_detached(id, addr);
+    let span = state.span(id);
+    span.in_scope(|| info!(profile = profile.name, "New basic connection."));
+    if has_proxy_hints(&headers) {
+        state.flag_proxied(id);
+    }
+    if state.start_download(id, Arc::clone(&profile)).is_none() {
+        return Redirect::to("/basic").into_response();
+    }
+    tokio::spawn(
+        {
+            let state = state.clone();
+            let duration = Duration::from_secs(profile.duration);
+            async move {
+                sleep(duration).await;
+                state.stop_download(id);
+                state.remove(id);
+            }
+        }
+        .instrument(span),
+    );
+    download_response(state, id, profile.start_size, 0, writes)
+}
+
+pub(crate) async fn download(
+    State(state): State<AppState>,
+    writes: Option<Extension<ConnectionWrites>>,
+    Path(id): Path<Uuid>,
+    Query(DownloadQuery {
+        size,
+        i: counter,
+        ts: timestamp,
+        ..
+    }): Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(result) = state.results.get(&id)
+        && let Some(profile) = state.profile(result.profile.as_deref())
+    {
+        return Html(
+            BasicResultsTemplate {
+                id,
+                download: bps_to_string(result.download_bps.unwrap_or_default()),
+                latency: seconds_to_string(result.latency.unwrap_or_default()),
+                max_upload_size: bytes_to_string(profile.max_upload_size),
+            }
+            .render()
+            .unwrap(),
+        )
+        .into_response();
+    }
+    if has_proxy_hints(&headers) {
+        state.flag_proxied(id);
+    }
+    state.measure_download_latency(id, timestamp, 0, counter);
+    download_response(state, id, size, counter, writes)
+}
+
+fn download_response(
+    state: AppState,
+    id: Uuid,
+    size: usize,
+    counter: usize,
+    writes: Option<Extension<ConnectionWrites>>,
+) -> Response {
+    let size = size.min(state.max_download_bytes);
+    let Some(SessionSnapshot::Downloading {
+        profile,
+        elapsed,
+        download_bps,
+        latency,
+        ..
+    }) = state.snapshot(id)
+    else {
+        return Redirect::to("/basic").into_response();
+    };
+    let Some(profile) = state.profile(Some(&profile)) else {
+        return Redirect::to("/basic").into_response();
+    };
+    let header = BasicDownloadHeaderTemplate {
+        profile: profile.label.clone(),
+        progress: ((elapsed / profile.duration as f64).min(1.0) * 100.0) as u32,
+        download: bps_to_string(download_bps),
+        latency: seconds_to_string(latency),
+    };
+    (
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        measurement_headers(),
+        Body::new(DownloadBody::new(
+            state,
+            id,
+            size,
+            0,
+            counter,
+            DownloadFormat::Html {
+                header: Bytes::from(header.render().unwrap()),
+            },
+            writes.map(|Extension(writes)| writes),
+        )),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_browsers() {
+        let cases = [
+            (
+                Some("Lynx/2.9.0dev.12 libwww-FM/2.14 SSL-MM/1.4.1 GNUTLS/3.7.8"),
+                true,
+            ),
+            (Some("w3m/0.5.3+git20230121"), true),
+            (Some("Emacs-w3m/1.4.632 w3m/0.5.3"), true),
+            (
+                Some("Links (2.29; Linux 6.1.0-26-amd64 x86_64; GNU C 12.2; text)"),
+                true,
+            ),
+            (
+                Some("ELinks/0.16.1.1 (textmode; Linux 6.1.0-26-amd64 x86_64; 80x24-2)"),
+                true,
+            ),
+            (Some("ELinks (0.4pre5; Linux 2.6.10 i686; 80x25)"), true),
+            (
+                Some("Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0"),
+                false,
+            ),
+            (Some("Mozilla/5.0 (compatible; Lynx)"), false),
+            (Some("Linkscraper/1.0"), false),
+            (Some("curl/8.5.0"), false),
+            (None, false),
+        ];
+        for (user_agent, expected) in cases {
+            let mut headers = HeaderMap::new();
+            if let Some(user_agent) = user_agent {
+                headers.insert(header::USER_AGENT, user_agent.parse().unwrap());
+            }
+            assert_eq!(is_text_browser(&headers), expected, "{user_agent:?}");
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    env, fs,
+    net::{Ipv6Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use color_eyre::eyre::{Context, eyre};
+use serde::Deserialize;
+
+use crate::payload::{PAYLOAD_CHUNK_SIZE, PayloadFormat};
+
+pub(crate) static CONFIG_ENV: &str = "SPEEDTEST_CONFIG";
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) log_format: LogFormat,
+    pub(crate) port: u16,
+    pub(crate) node_name: Option<String>,
+    pub(crate) listeners: Vec<ListenerConfig>,
+    pub(crate) max_upload_size: usize,
+    pub(crate) payload_pool_size: usize,
+    pub(crate) payload_format: PayloadFormat,
+    pub(crate) shutdown_timeout: u64,
+    pub(crate) max_sessions: Option<usize>,
+    pub(crate) header_read_timeout: u64,
+    pub(crate) keep_alive_timeout: u64,
+    pub(crate) session_idle_timeout: u64,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) max_connections_per_ip: Option<usize>,
+    pub(crate) default_profile: String,
+    pub(crate) profiles: Vec<Profile>,
+    pub(crate) iperf3: Option<Iperf3Config>,
+    pub(crate) otlp: Option<OtlpConfig>,
+    pub(crate) privacy: PrivacyConfig,
+    pub(crate) geoip: Option<GeoIpConfig>,
+    pub(crate) auth: Option<AuthConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_format: LogFormat::Compact,
+            port: 3000,
+            node_name: None,
+            listeners: vec![],
+            max_upload_size: 250_000_000,
+            payload_pool_size: 16 * 1024 * 1024,
+            payload_format: PayloadFormat::Bitmap,
+            shutdown_timeout: 30,
+            max_sessions: None,
+            header_read_timeout: 30,
+            keep_alive_timeout: 60,
+            session_idle_timeout: 900,
+            max_connections: None,
+            max_connections_per_ip: None,
+            default_profile: "standard".into(),
+            profiles: vec![
+                Profile {
+                    name: "quick".into(),
+                    label: "Quick test".into(),
+                    duration: 5,
+                    start_size: 2_000_000,
+                    max_size: 20_000_000,
+                    streams: 1,
+                    max_upload_size: 25_000_000,
+                },
+                Profile {
+                    name: "standard".into(),
+                    label: "Start test!".into(),
+                    duration: 15,
+                    start_size: 10_000_000,
+                    max_size: 100_000_000,
+                    streams: 1,
+                    max_upload_size: 250_000_000,
+                },
+                Profile {
+                    name: "extended".into(),
+                    label: "Extended test".into(),
+                    duration: 60,
+                    start_size: 10_000_000,
+                    max_size: 100_000_000,
+                    streams: 4,
+                    max_upload_size: 1_000_000_000,
+                },
+            ],
+            iperf3: None,
+            otlp: None,
+            privacy: PrivacyConfig::default(),
+            geoip: None,
+            auth: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LogFormat {
+    Compact,
+    /// One JSON object per line, with the fields of the enclosing spans.
+    Json,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Profile {
+    pub(crate) name: String,
+    pub(crate) label: String,
+    pub(crate) duration: u64,
+    pub(crate) start_size: usize,
+    pub(crate) max_size: usize,
+    #[serde(default = "default_streams")]
+    pub(crate) streams: usize,
+    pub(crate) max_upload_size: usize,
+}
+
+fn default_streams() -> usize {
+    1
+}
+
+impl Profile {
+    /// Size of the download request following the given one, growing by the
+    /// start size every request.
+    pub(crate) fn next_size(&self, counter: usize) -> usize {
+        self.start_size
+            .saturating_mul(counter + 2)
+            .min(self.max_size)
+    }
+}
+
+/// Address to accept HTTP connections on, either a TCP `address` or the `path`
+/// of a Unix domain socket.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListenerConfig {
+    pub(crate) address: Option<SocketAddr>,
+    pub(crate) path: Option<PathBuf>,
+    /// Expect a PROXY protocol (v1 or v2) header before every connection.
+    #[serde(default)]
+    pub(crate) proxy_protocol: bool,
+    pub(crate) tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsConfig {
+    pub(crate) certificate: PathBuf,
+    pub(crate) private_key: PathBuf,
+    /// CA bundle to verify client certificates against. Clients may still
+    /// connect without one.
+    pub(crate) client_ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Iperf3Config {
+    pub(crate) port: u16,
+    pub(crate) max_duration: u64,
+}
+
+impl Default for Iperf3Config {
+    fn default() -> Self {
+        Iperf3Config {
+            port: 5201,
+            max_duration: 60,
+        }
+    }
+}
+
+/// OpenTelemetry collector to export traces and metrics to.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OtlpConfig {
+    /// Base URL of the collector, defaulting to the standard `OTEL_EXPORTER_OTLP_*`
+    /// environment variables.
+    pub(crate) endpoint: Option<String>,
+    pub(crate) protocol: OtlpProtocol,
+    pub(crate) service_name: String,
+    pub(crate) metrics_interval: u64,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        OtlpConfig {
+            endpoint: None,
+            protocol: OtlpProtocol::Grpc,
+            service_name: "no-js-speedtest".into(),
+            metrics_interval: 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OtlpProtocol {
+    Grpc,
+    /// Protobuf over HTTP.
+    Http,
+}
+
+/// How client addresses are logged and stored, and for how long results are kept.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PrivacyConfig {
+    pub(crate) ip_anonymization: IpAnonymization,
+    pub(crate) ip_hash_key: Option<String>,
+    pub(crate) results_retention: u64,
+    /// Period after which addresses are stripped from results, if shorter
+    /// than the results retention.
+    pub(crate) ip_retention: Option<u64>,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        PrivacyConfig {
+            ip_anonymization: IpAnonymization::None,
+            ip_hash_key: None,
+            results_retention: 24 * 60 * 60,
+            ip_retention: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IpAnonymization {
+    None,
+    /// Keep the /24 network of IPv4 addresses and the /48 network of IPv6 ones.
+    Truncate,
+    /// HMAC-SHA256 of the address with `ip_hash_key`.
+    Hash,
+}
+
+/// MaxMind DB files (from MaxMind or DB-IP) to look client addresses up in.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct GeoIpConfig {
+    /// ASN or ISP database.
+    pub(crate) asn_database: Option<PathBuf>,
+    /// City or country database.
+    pub(crate) location_database: Option<PathBuf>,
+}
+
+/// Credentials for the `/admin` dashboard and, optionally, the API, which are
+/// otherwise disabled or public.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuthConfig {
+    /// Require the read-only role for the session and result APIs too.
+    pub(crate) protect_api: bool,
+    pub(crate) tokens: Vec<TokenConfig>,
+    pub(crate) users: Vec<UserConfig>,
+    pub(crate) certificates: Vec<CertificateConfig>,
+}
+
+/// Roles in increasing order of privilege.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Role {
+    /// Can view the dashboard and the API.
+    ReadOnly,
+    /// Can also close sessions.
+    Operator,
+}
+
+/// Static `Authorization: Bearer` token.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TokenConfig {
+    pub(crate) token: String,
+    pub(crate) role: Role,
+}
+
+/// HTTP Basic user, with an Argon2 password hash in PHC string format.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UserConfig {
+    pub(crate) username: String,
+    pub(crate) password_hash: String,
+    pub(crate) role: Role,
+}
+
+/// Client certificate, verified against the `client_ca` of a TLS listener and
+/// matched by its subject common name.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CertificateConfig {
+    pub(crate) common_name: String,
+    pub(crate) role: Role,
+}
+
+impl Config {
+    pub(crate) fn load() -> color_eyre::Result<Self> {
+        let mut config = match env::var_os(CONFIG_ENV) {
+            Some(path) => {
+                let contents = fs::read_to_string(&path)
+                    .wrap_err_with(|| format!("failed to read config file {path:?}"))?;
+                let config: Config = toml::from_str(&contents)
+                    .wrap_err_with(|| format!("failed to parse config file {path:?}"))?;
+                config.validate()?;
+                config
+            }
+            None => Config::default(),
+        };
+        if config.listeners.is_empty() {
+            config.listeners.push(ListenerConfig {
+                address: Some((Ipv6Addr::UNSPECIFIED, config.port).into()),
+                path: None,
+                proxy_protocol: false,
+                tls: None,
+            });
+        }
+        Ok(config)
+    }
+
+    fn validate(&self) -> color_eyre::Result<()> {
+        if self.payload_pool_size < PAYLOAD_CHUNK_SIZE {
+            return Err(eyre!(
+                "payload pool size must be at least {PAYLOAD_CHUNK_SIZE} bytes"
+            ));
+        }
+        if self.header_read_timeout == 0
+            || self.keep_alive_timeout == 0
+            || self.session_idle_timeout == 0
+        {
+            return Err(eyre!("timeouts must be at least one second"));
+        }
+        if self.privacy.results_retention == 0 {
+            return Err(eyre!("results retention must be at least one second"));
+        }
+        if let Some(otlp) = &self.otlp
+            && otlp.metrics_interval == 0
+        {
+            return Err(eyre!("OTLP metrics interval must be at least one second"));
+        }
+        if let Some(auth) = &self.auth
+            && auth.tokens.iter().any(|token| token.token.is_empty())
+        {
+            return Err(eyre!("auth tokens must not be empty"));
+        }
+        for listener in &self.listeners {
+            if listener.address.is_some() == listener.path.is_some() {
+                return Err(eyre!("listeners need exactly one of address or path"));
+            }
+        }
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if profile.name.is_empty()
+                || !profile
+                    .name
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+            {
+                return Err(eyre!("invalid profile name {:?}", profile.name));
+            }
+            if self.profiles[..i]
+                .iter()
+                .any(|other| other.name == profile.name)
+            {
+                return Err(eyre!("duplicate profile {:?}", profile.name));
+            }
+            if profile.duration == 0
+                || profile.streams == 0
+                || profile.start_size == 0
+                || profile.start_size > profile.max_size
+            {
+                return Err(eyre!("invalid settings for profile {:?}", profile.name));
+            }
+        }
+        if !self
+            .profiles
+            .iter()
+            .any(|profile| profile.name == self.default_profile)
+        {
+            return Err(eyre!(
+                "default profile {:?} is not defined",
+                self.default_profile
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    os::fd::{AsRawFd, RawFd},
+    sync::{
+        Arc, Mutex, PoisonError, RwLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use axum::http::{HeaderMap, Version};
+use rustls::{ProtocolVersion, ServerConnection};
+use tokio::net::TcpStream;
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use crate::{session::AppState, utils::client_ip};
+
+/// Raw descriptor of a TCP connection, for socket options that tokio does not
+/// expose. It is only borrowed: the connection [releases](Self::release) it
+/// before closing the socket, after which every query returns `None`.
+#[derive(Clone)]
+pub(crate) struct ConnectionSocket(Arc<RwLock<Option<RawFd>>>);
+
+impl ConnectionSocket {
+    pub(crate) fn new(tcp_stream: &TcpStream) -> Self {
+        ConnectionSocket(Arc::new(RwLock::new(Some(tcp_stream.as_raw_fd()))))
+    }
+
+    /// Forgets the descriptor, waiting for queries in progress to finish.
+    pub(crate) fn release(&self) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        let fd = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        let mut tcp_info = std::mem::MaybeUninit::<libc::tcp_info>::zeroed();
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        // SAFETY: the connection keeps the descriptor open until it is
+        // released, which the read lock holds off, and `tcp_info` is a
+        // properly sized buffer for `TCP_INFO`.
+        let result = unsafe {
+            libc::getsockopt(
+                (*fd)?,
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                tcp_info.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        if result != 0 {
+            return None;
+        }
+        // SAFETY: `getsockopt` succeeded, and the buffer was zero-initialized.
+        let tcp_info = unsafe { tcp_info.assume_init() };
+        Some(Duration::from_micros(tcp_info.tcpi_rtt.into()))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Count of response bytes written to a connection, so that bodies can wait
+/// for their previous frame to reach the socket.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionWrites(Arc<WritesInner>);
+
+#[derive(Default)]
+struct WritesInner {
+    written: AtomicUsize,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl ConnectionWrites {
+    pub(crate) fn written(&self) -> usize {
+        self.0.written.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn add(&self, size: usize) {
+        self.0.written.fetch_add(size, Ordering::AcqRel);
+        let waker = self
+            .0
+            .waker
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Ready once `target` bytes have been written in total.
+    pub(crate) fn poll_written(&self, cx: &mut Context<'_>, target: usize) -> Poll<()> {
+        if self.written() >= target {
+            return Poll::Ready(());
+        }
+        *self.0.waker.lock().unwrap_or_else(PoisonError::into_inner) = Some(cx.waker().clone());
+        // A write may have happened before the waker was stored.
+        if self.written() >= target {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Negotiated parameters of a TLS connection.
+#[derive(Clone)]
+pub(crate) struct TlsInfo {
+    pub(crate) version: &'static str,
+    pub(crate) cipher: &'static str,
+    /// Subject common name of the verified client certificate, if any.
+    pub(crate) client_name: Option<Arc<str>>,
+}
+
+impl TlsInfo {
+    pub(crate) fn new(connection: &ServerConnection) -> Option<Self> {
+        let version = match connection.protocol_version()? {
+            ProtocolVersion::TLSv1_2 => "TLS 1.2",
+            ProtocolVersion::TLSv1_3 => "TLS 1.3",
+            version => version.as_str()?,
+        };
+        let cipher = connection.negotiated_cipher_suite()?.suite().as_str()?;
+        let client_name = connection
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .and_then(|certificate| X509Certificate::from_der(certificate).ok())
+            .and_then(|(_, certificate)| {
+                certificate
+                    .subject()
+                    .iter_common_name()
+                    .next()
+                    .and_then(|common_name| common_name.as_str().ok())
+                    .map(Arc::from)
+            });
+        Some(TlsInfo {
+            version,
+            cipher,
+            client_name,
+        })
+    }
+}
+
+/// Connection of a request as the server sees it, shown to the user.
+pub(crate) struct ConnectionDetails {
+    pub(crate) client_ip: String,
+    pub(crate) ip_version: &'static str,
+    /// Whether the request came through a proxy that set `X-Forwarded-For`.
+    pub(crate) forwarded: bool,
+    pub(crate) http_version: &'static str,
+    pub(crate) tls: Option<TlsInfo>,
+    pub(crate) node_name: Arc<str>,
+}
+
+impl ConnectionDetails {
+    pub(crate) fn new(
+        state: &AppState,
+        addr: SocketAddr,
+        headers: &HeaderMap,
+        version: Version,
+        tls: Option<TlsInfo>,
+    ) -> Self {
+        let client_ip = client_ip(headers, addr);
+        ConnectionDetails {
+            client_ip: client_ip.to_string(),
+            ip_version: match client_ip {
+                IpAddr::V4(_) => "IPv4",
+                IpAddr::V6(_) => "IPv6",
+            },
+            forwarded: headers.contains_key("X-Forwarded-For"),
+            http_version: match version {
+                Version::HTTP_09 => "HTTP/0.9",
+                Version::HTTP_10 => "HTTP/1.0",
+                Version::HTTP_11 => "HTTP/1.1",
+                Version::HTTP_2 => "HTTP/2",
+                Version::HTTP_3 => "HTTP/3",
+                _ => "HTTP",
+            },
+            tls,
+            node_name: Arc::clone(&state.node_name),
+        }
+    }
+}
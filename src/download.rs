@@ -1,34 +1,103 @@
 use std::{
     pin::Pin,
-    sync::OnceLock,
-    task::{Context, Poll},
+    task::{Context, Poll, ready},
+    time::Instant,
 };
 
 use askama::Template;
 use bytes::Bytes;
 use http_body::{Body as HttpBody, Frame};
+use tracing::{Instrument, Span, info_span};
 use uuid::Uuid;
 
-use crate::{session::AppState, templates::DownloadTemplate};
+use crate::{
+    connection::ConnectionWrites,
+    measure::ChunkTimings,
+    payload::{PayloadFormat, PayloadWriter},
+    session::AppState,
+    templates::{BasicDownloadFooterTemplate, DownloadTemplate},
+};
 
-pub(crate) static RANDOM_BITMAP: OnceLock<Bytes> = OnceLock::new();
+pub(crate) enum DownloadFormat {
+    Payload(PayloadFormat),
+    /// Payload embedded in an HTML comment, followed by a refresh to the next request.
+    Html {
+        header: Bytes,
+    },
+}
 
-pub(crate) enum DownloadState {
+enum DownloadState {
     Waiting,
-    Polled,
+    Sending,
+    Footer,
+    Polled(PayloadFormat),
     Done,
 }
 
+/// Body of a download request, emitted in frames of at most
+/// [`PAYLOAD_CHUNK_SIZE`](crate::payload::PAYLOAD_CHUNK_SIZE) bytes.
+///
+/// Every frame is held back until the previous one has been written to the
+/// connection, which wakes the body, so that it is timestamped as it reaches
+/// the socket rather than hyper's write buffer. The steady part of the request
+/// is then reported along with its size.
 pub(crate) struct DownloadBody {
-    pub(crate) app_state: AppState,
-    pub(crate) id: Uuid,
-    pub(crate) size: usize,
-    pub(crate) counter: usize,
-    pub(crate) download_state: DownloadState,
+    app_state: AppState,
+    id: Uuid,
+    size: usize,
+    stream: usize,
+    counter: usize,
+    format: DownloadFormat,
+    payload: PayloadWriter,
+    timings: ChunkTimings,
+    writes: Option<ConnectionWrites>,
+    unacknowledged: usize,
+    /// Bytes written to the connection once the unacknowledged frame is.
+    acknowledged_at: usize,
+    download_state: DownloadState,
+    span: Span,
 }
 
-pub(crate) static DOWNLOAD_TEST_DURATION: u64 = 15;
-pub(crate) static DOWNLOAD_START_SIZE: usize = 10_000_000;
+impl DownloadBody {
+    pub(crate) fn new(
+        app_state: AppState,
+        id: Uuid,
+        size: usize,
+        stream: usize,
+        counter: usize,
+        format: DownloadFormat,
+        writes: Option<ConnectionWrites>,
+    ) -> Self {
+        let payload = match format {
+            DownloadFormat::Payload(payload_format) => PayloadWriter::new(payload_format, size),
+            DownloadFormat::Html { .. } => PayloadWriter::text(size),
+        };
+        let span = info_span!(parent: &app_state.span(id), "download", stream, counter, size);
+        DownloadBody {
+            app_state,
+            id,
+            size: payload.size(),
+            stream,
+            counter,
+            format,
+            payload,
+            timings: ChunkTimings::new(Instant::now()),
+            writes,
+            unacknowledged: 0,
+            acknowledged_at: 0,
+            download_state: DownloadState::Waiting,
+            span,
+        }
+    }
+
+    fn send(&mut self, frame: Bytes) -> Poll<Option<Result<Frame<Bytes>, color_eyre::Report>>> {
+        self.unacknowledged = frame.len();
+        if let Some(writes) = &self.writes {
+            self.acknowledged_at = writes.written() + frame.len();
+        }
+        Poll::Ready(Some(Ok(Frame::data(frame))))
+    }
+}
 
 impl HttpBody for DownloadBody {
     type Data = Bytes;
@@ -37,52 +106,101 @@ impl HttpBody for DownloadBody {
 
     fn poll_frame(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.unacknowledged > 0 {
+            if let Some(writes) = &self.writes {
+                ready!(writes.poll_written(cx, self.acknowledged_at));
+            }
+            let size = std::mem::take(&mut self.unacknowledged);
+            self.timings.record(size);
+        }
+        if let DownloadState::Waiting = self.download_state {
+            self.download_state = DownloadState::Sending;
+            self.timings = ChunkTimings::new(Instant::now());
+            if let DownloadFormat::Html { header } = &self.format {
+                let header = header.clone();
+                return self.send(header);
+            }
+        }
+        if let DownloadState::Sending = self.download_state {
+            match self.payload.next_frame() {
+                Some(chunk) => return self.send(chunk),
+                None => {
+                    self.download_state = match self.format {
+                        DownloadFormat::Payload(format) => DownloadState::Polled(format),
+                        DownloadFormat::Html { .. } => DownloadState::Footer,
+                    };
+                }
+            }
+        }
         match self.download_state {
-            DownloadState::Waiting => {
-                self.download_state = DownloadState::Polled;
-                Poll::Ready(Some(Ok(Frame::data(
-                    RANDOM_BITMAP.get().unwrap().slice(0..self.size),
-                ))))
+            DownloadState::Footer => {
+                self.download_state = DownloadState::Done;
+                let (download, latency, timestamp, next_size) =
+                    match self.app_state.measure_download_bandwidth(
+                        self.id,
+                        self.size,
+                        self.timings.steady_state(),
+                        self.stream,
+                        self.counter,
+                    ) {
+                        Some(measurement) => (
+                            measurement.download,
+                            measurement.latency,
+                            measurement.start.elapsed().as_secs_f64(),
+                            measurement.profile.next_size(self.counter),
+                        ),
+                        None => ("--".into(), "--".into(), 0.0, self.size),
+                    };
+                let html = BasicDownloadFooterTemplate {
+                    id: self.id,
+                    next_size,
+                    counter: self.counter + 1,
+                    timestamp,
+                    download,
+                    latency,
+                };
+                Poll::Ready(Some(Ok(Frame::data(Bytes::from(html.render().unwrap())))))
             }
-            DownloadState::Polled => {
+            DownloadState::Polled(format) => {
                 self.download_state = DownloadState::Done;
                 let id = self.id;
                 let size = self.size;
                 let state = self.app_state.clone();
+                let stream = self.stream;
                 let counter = self.counter;
-                tokio::spawn(async move {
-                    if let Some((sender, download, latency, instant)) =
-                        state.measure_download_bandwidth(id, size, counter)
-                    {
-                        let next_size = match counter {
-                            0 => 20_000_000,
-                            1 => 30_000_000,
-                            2 => 40_000_000,
-                            3 => 50_000_000,
-                            4 => 60_000_000,
-                            5 => 70_000_000,
-                            6 => 80_000_000,
-                            7 => 90_000_000,
-                            8.. => 100_000_000,
-                        };
-                        if let Some(permit) = sender.reserve().await {
+                let steady_state = self.timings.steady_state();
+                tokio::spawn(
+                    async move {
+                        if let Some(measurement) = state.measure_download_bandwidth(
+                            id,
+                            size,
+                            steady_state,
+                            stream,
+                            counter,
+                        ) && let Some(permit) = measurement.sender.reserve().await
+                        {
                             let html = DownloadTemplate {
                                 id,
-                                next_size,
+                                next_size: measurement.profile.next_size(counter),
+                                extension: format.extension(),
+                                stream,
                                 counter: counter + 1,
-                                download,
-                                latency,
-                                timestamp: instant.elapsed().as_secs_f64(),
+                                download: measurement.download,
+                                latency: measurement.latency,
+                                timestamp: measurement.start.elapsed().as_secs_f64(),
                             };
                             permit.send(Bytes::from(html.render().unwrap()));
                         }
                     }
-                });
+                    .instrument(self.span.clone()),
+                );
+                Poll::Ready(None)
+            }
+            DownloadState::Waiting | DownloadState::Sending | DownloadState::Done => {
                 Poll::Ready(None)
             }
-            DownloadState::Done => Poll::Ready(None),
         }
     }
 
@@ -93,7 +211,14 @@ impl HttpBody for DownloadBody {
                 size_hint.set_lower(self.size as u64);
                 size_hint
             }
-            DownloadState::Polled | DownloadState::Done => http_body::SizeHint::default(),
+            DownloadState::Sending => {
+                let mut size_hint = http_body::SizeHint::new();
+                size_hint.set_lower(self.payload.remaining() as u64);
+                size_hint
+            }
+            DownloadState::Footer | DownloadState::Polled(_) | DownloadState::Done => {
+                http_body::SizeHint::default()
+            }
         }
     }
 }
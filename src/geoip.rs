@@ -0,0 +1,410 @@
+use std::{collections::BTreeMap, net::IpAddr, path::Path};
+
+use color_eyre::eyre::Context;
+use maxminddb::Reader;
+use serde::{Deserialize, Serialize};
+
+use crate::config::GeoIpConfig;
+
+/// Fields shared by the ASN and ISP databases of MaxMind and DB-IP.
+#[derive(Deserialize)]
+struct AsnRecord {
+    autonomous_system_number: Option<u32>,
+    autonomous_system_organization: Option<String>,
+    isp: Option<String>,
+}
+
+/// Fields shared by the city and country databases of MaxMind and DB-IP.
+#[derive(Deserialize)]
+struct LocationRecord {
+    city: Option<NamedRecord>,
+    #[serde(default)]
+    subdivisions: Vec<NamedRecord>,
+    country: Option<NamedRecord>,
+}
+
+#[derive(Deserialize)]
+struct NamedRecord {
+    iso_code: Option<String>,
+    #[serde(default)]
+    names: BTreeMap<String, String>,
+}
+
+impl NamedRecord {
+    fn name(&self) -> Option<String> {
+        self.names.get("en").cloned()
+    }
+}
+
+/// Network and approximate location of a client address.
+#[derive(Clone, Default, Serialize)]
+pub(crate) struct NetworkInfo {
+    pub(crate) asn: Option<u32>,
+    pub(crate) isp: Option<String>,
+    pub(crate) city: Option<String>,
+    pub(crate) region: Option<String>,
+    pub(crate) country: Option<String>,
+    pub(crate) country_code: Option<String>,
+}
+
+impl NetworkInfo {
+    /// Provider as shown to users, such as "Example ISP (AS64496)".
+    pub(crate) fn provider(&self) -> Option<String> {
+        match (&self.isp, self.asn) {
+            (Some(isp), Some(asn)) => Some(format!("{isp} (AS{asn})")),
+            (Some(isp), None) => Some(isp.clone()),
+            (None, Some(asn)) => Some(format!("AS{asn}")),
+            (None, None) => None,
+        }
+    }
+
+    /// Location as shown to users, such as "Lisbon, Portugal".
+    pub(crate) fn location(&self) -> Option<String> {
+        let parts: Vec<_> = [&self.city, &self.region, &self.country]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+}
+
+/// Local MaxMind DB files, read once at startup so lookups never hit the network.
+pub(crate) struct GeoIp {
+    asn: Option<Reader<Vec<u8>>>,
+    location: Option<Reader<Vec<u8>>>,
+}
+
+fn open_database(path: &Path) -> color_eyre::Result<Reader<Vec<u8>>> {
+    Reader::open_readfile(path).wrap_err_with(|| format!("failed to open GeoIP database {path:?}"))
+}
+
+impl GeoIp {
+    pub(crate) fn open(config: &GeoIpConfig) -> color_eyre::Result<Self> {
+        Ok(GeoIp {
+            asn: config
+                .asn_database
+                .as_deref()
+                .map(open_database)
+                .transpose()?,
+            location: config
+                .location_database
+                .as_deref()
+                .map(open_database)
+                .transpose()?,
+        })
+    }
+
+    pub(crate) fn lookup(&self, ip: IpAddr) -> Option<NetworkInfo> {
+        let ip = ip.to_canonical();
+        let mut info = NetworkInfo::default();
+        if let Some(asn) = &self.asn
+            && let Ok(record) = asn.lookup::<AsnRecord>(ip)
+        {
+            info.asn = record.autonomous_system_number;
+            info.isp = record.isp.or(record.autonomous_system_organization);
+        }
+        if let Some(location) = &self.location
+            && let Ok(record) = location.lookup::<LocationRecord>(ip)
+        {
+            info.city = record.city.and_then(|city| city.name());
+            info.region = record
+                .subdivisions
+                .first()
+                .and_then(|subdivision| subdivision.name());
+            info.country_code = record
+                .country
+                .as_ref()
+                .and_then(|country| country.iso_code.clone());
+            info.country = record.country.and_then(|country| country.name());
+        }
+        (info.provider().is_some() || info.location().is_some()).then_some(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    /// Encodes maps, arrays, short strings and unsigned integers in the MaxMind
+    /// DB data format.
+    fn encode(value: &Value, data: &mut Vec<u8>) {
+        let control = |data: &mut Vec<u8>, kind: u8, size: usize| {
+            let (size_bits, extra_size) = match size {
+                0..29 => (size as u8, None),
+                29..285 => (29, Some((size - 29) as u8)),
+                _ => unimplemented!("size {size}"),
+            };
+            if kind > 7 {
+                data.extend_from_slice(&[size_bits, kind - 7]);
+            } else {
+                data.push(kind << 5 | size_bits);
+            }
+            data.extend(extra_size);
+        };
+        match value {
+            Value::Object(map) => {
+                control(data, 7, map.len());
+                for (key, value) in map {
+                    encode(&Value::from(key.as_str()), data);
+                    encode(value, data);
+                }
+            }
+            Value::Array(values) => {
+                control(data, 11, values.len());
+                for value in values {
+                    encode(value, data);
+                }
+            }
+            Value::String(string) => {
+                control(data, 2, string.len());
+                data.extend_from_slice(string.as_bytes());
+            }
+            Value::Number(number) => {
+                control(data, 6, 4);
+                data.extend_from_slice(&(number.as_u64().unwrap() as u32).to_be_bytes());
+            }
+            _ => unimplemented!("{value}"),
+        }
+    }
+
+    /// IPv4 database with 24-bit records, mapping each network to a record.
+    fn database(networks: &[(&str, u8, Value)]) -> Reader<Vec<u8>> {
+        #[derive(Clone, Copy)]
+        enum Child {
+            Empty,
+            Node(usize),
+            Data(usize),
+        }
+        let mut nodes = vec![[Child::Empty; 2]];
+        let mut data = vec![];
+        for (network, prefix_len, record) in networks {
+            let network = u32::from(network.parse::<std::net::Ipv4Addr>().unwrap());
+            let mut node = 0;
+            for bit in 0..*prefix_len {
+                let side = (network >> (31 - bit) & 1) as usize;
+                if bit + 1 == *prefix_len {
+                    nodes[node][side] = Child::Data(data.len());
+                } else if let Child::Node(next) = nodes[node][side] {
+                    node = next;
+                } else {
+                    nodes.push([Child::Empty; 2]);
+                    nodes[node][side] = Child::Node(nodes.len() - 1);
+                    node = nodes.len() - 1;
+                }
+            }
+            encode(record, &mut data);
+        }
+        let node_count = nodes.len();
+        let mut buffer = vec![];
+        for child in nodes.into_iter().flatten() {
+            let record = match child {
+                Child::Empty => node_count,
+                Child::Node(node) => node,
+                Child::Data(offset) => node_count + 16 + offset,
+            };
+            buffer.extend_from_slice(&(record as u32).to_be_bytes()[1..]);
+        }
+        buffer.extend_from_slice(&[0; 16]);
+        buffer.extend_from_slice(&data);
+        buffer.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        encode(
+            &json!({
+                "binary_format_major_version": 2,
+                "binary_format_minor_version": 0,
+                "build_epoch": 0,
+                "database_type": "Test",
+                "description": {},
+                "ip_version": 4,
+                "languages": ["en"],
+                "node_count": node_count,
+                "record_size": 24,
+            }),
+            &mut buffer,
+        );
+        Reader::from_source(buffer).unwrap()
+    }
+
+    fn named(name: &str, iso_code: Option<&str>) -> Value {
+        let mut record = json!({"names": {"en": name, "de": "Anders"}});
+        if let Some(iso_code) = iso_code {
+            record["iso_code"] = iso_code.into();
+        }
+        record
+    }
+
+    fn geoip() -> GeoIp {
+        GeoIp {
+            asn: Some(database(&[
+                ("192.0.2.0", 24, json!({"autonomous_system_number": 64496})),
+                (
+                    "198.51.100.0",
+                    24,
+                    json!({
+                        "autonomous_system_number": 64497,
+                        "autonomous_system_organization": "Example Transit",
+                    }),
+                ),
+                (
+                    "203.0.113.0",
+                    24,
+                    json!({
+                        "autonomous_system_number": 64498,
+                        "autonomous_system_organization": "Example Holding",
+                        "isp": "Example ISP",
+                    }),
+                ),
+            ])),
+            location: Some(database(&[
+                (
+                    "192.0.2.0",
+                    24,
+                    json!({
+                        "city": named("Coimbra", None),
+                        "subdivisions": [named("Coimbra District", Some("06"))],
+                        "country": named("Portugal", Some("PT")),
+                    }),
+                ),
+                (
+                    "198.51.100.0",
+                    24,
+                    json!({"country": named("Germany", Some("DE"))}),
+                ),
+                (
+                    "203.0.113.0",
+                    24,
+                    json!({"city": named("Springfield", None)}),
+                ),
+            ])),
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let geoip = geoip();
+        let cases = [
+            (
+                "192.0.2.1",
+                Some((
+                    Some("AS64496"),
+                    Some("Coimbra, Coimbra District, Portugal"),
+                    Some("PT"),
+                )),
+            ),
+            (
+                "::ffff:192.0.2.1",
+                Some((
+                    Some("AS64496"),
+                    Some("Coimbra, Coimbra District, Portugal"),
+                    Some("PT"),
+                )),
+            ),
+            (
+                "198.51.100.7",
+                Some((
+                    Some("Example Transit (AS64497)"),
+                    Some("Germany"),
+                    Some("DE"),
+                )),
+            ),
+            (
+                "203.0.113.9",
+                Some((Some("Example ISP (AS64498)"), Some("Springfield"), None)),
+            ),
+            ("10.0.0.1", None),
+            ("192.168.1.1", None),
+            ("127.0.0.1", None),
+        ];
+        for (ip, expected) in cases {
+            let info = geoip.lookup(ip.parse().unwrap());
+            assert_eq!(
+                info.as_ref().map(|info| (
+                    info.provider(),
+                    info.location(),
+                    info.country_code.clone()
+                )),
+                expected.map(|(provider, location, country_code)| (
+                    provider.map(String::from),
+                    location.map(String::from),
+                    country_code.map(String::from)
+                )),
+                "{ip}"
+            );
+        }
+    }
+
+    #[test]
+    fn single_database() {
+        let geoip = GeoIp {
+            location: None,
+            ..geoip()
+        };
+        let info = geoip.lookup("198.51.100.7".parse().unwrap()).unwrap();
+        assert_eq!(info.location(), None);
+        assert_eq!(
+            info.provider().as_deref(),
+            Some("Example Transit (AS64497)")
+        );
+        assert!(geoip.lookup("10.0.0.1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn provider() {
+        let cases = [
+            (
+                Some("Example ISP"),
+                Some(64496),
+                Some("Example ISP (AS64496)"),
+            ),
+            (Some("Example ISP"), None, Some("Example ISP")),
+            (None, Some(64496), Some("AS64496")),
+            (None, None, None),
+        ];
+        for (isp, asn, expected) in cases {
+            let info = NetworkInfo {
+                asn,
+                isp: isp.map(Into::into),
+                ..NetworkInfo::default()
+            };
+            assert_eq!(info.provider().as_deref(), expected, "{isp:?} {asn:?}");
+        }
+    }
+
+    #[test]
+    fn location() {
+        let cases = [
+            (
+                Some("Lisbon"),
+                Some("Lisbon District"),
+                Some("Portugal"),
+                Some("Lisbon, Lisbon District, Portugal"),
+            ),
+            (
+                Some("Lisbon"),
+                None,
+                Some("Portugal"),
+                Some("Lisbon, Portugal"),
+            ),
+            (
+                None,
+                Some("Lisbon District"),
+                Some("Portugal"),
+                Some("Lisbon District, Portugal"),
+            ),
+            (None, None, Some("Portugal"), Some("Portugal")),
+            (Some("Lisbon"), None, None, Some("Lisbon")),
+            (None, None, None, None),
+        ];
+        for (city, region, country, expected) in cases {
+            let info = NetworkInfo {
+                city: city.map(Into::into),
+                region: region.map(Into::into),
+                country: country.map(Into::into),
+                ..NetworkInfo::default()
+            };
+            assert_eq!(info.location().as_deref(), expected, "{expected:?}");
+        }
+    }
+}
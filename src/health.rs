@@ -0,0 +1,100 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use color_eyre::eyre::{Context, eyre};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    time::timeout,
+};
+
+use crate::{config::Config, session::AppState};
+
+static HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) async fn healthz() -> impl IntoResponse {
+    "ok\n"
+}
+
+pub(crate) async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    if state.draining.load(Ordering::Acquire) {
+        (StatusCode::SERVICE_UNAVAILABLE, "draining\n")
+    } else if state.at_session_limit() {
+        (StatusCode::SERVICE_UNAVAILABLE, "session limit reached\n")
+    } else {
+        (StatusCode::OK, "ok\n")
+    }
+}
+
+/// Requests `/healthz` from the first plain-text listener of the local server,
+/// for container images without an HTTP client.
+pub(crate) async fn check(config: &Config) -> color_eyre::Result<()> {
+    let listener = config
+        .listeners
+        .iter()
+        .find(|listener| listener.tls.is_none())
+        .ok_or_else(|| eyre!("no listener without TLS to check"))?;
+    let mut request = vec![];
+    if listener.proxy_protocol {
+        request.extend_from_slice(b"PROXY UNKNOWN\r\n");
+    }
+    request.extend_from_slice(
+        b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    let status_line = timeout(HEALTHCHECK_TIMEOUT, async {
+        let response = match (&listener.address, &listener.path) {
+            (Some(address), _) => {
+                // Hosts with IPv6 disabled on the loopback interface have no
+                // `::1`, but still reach a dual-stack `[::]` listener over IPv4.
+                let ips: &[IpAddr] = match address.ip() {
+                    IpAddr::V4(ip) if ip.is_unspecified() => &[Ipv4Addr::LOCALHOST.into()],
+                    IpAddr::V6(ip) if ip.is_unspecified() => {
+                        &[Ipv6Addr::LOCALHOST.into(), Ipv4Addr::LOCALHOST.into()]
+                    }
+                    ip => &[ip],
+                };
+                let addrs: Vec<_> = ips.iter().map(|&ip| (ip, address.port()).into()).collect();
+                let stream = TcpStream::connect(addrs.as_slice())
+                    .await
+                    .wrap_err_with(|| format!("failed to connect to {address}"))?;
+                exchange(stream, &request).await?
+            }
+            (None, Some(path)) => {
+                let stream = UnixStream::connect(path)
+                    .await
+                    .wrap_err_with(|| format!("failed to connect to {path:?}"))?;
+                exchange(stream, &request).await?
+            }
+            (None, None) => return Err(eyre!("listener has no address or path")),
+        };
+        let response = String::from_utf8_lossy(&response);
+        Ok(response.lines().next().unwrap_or_default().to_owned())
+    })
+    .await
+    .wrap_err("health check timed out")??;
+    if status_line.split(' ').nth(1) == Some("200") {
+        Ok(())
+    } else {
+        Err(eyre!("unhealthy: {status_line:?}"))
+    }
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+) -> color_eyre::Result<Vec<u8>> {
+    stream
+        .write_all(request)
+        .await
+        .wrap_err("failed to send request")?;
+    let mut response = vec![];
+    stream
+        .read_to_end(&mut response)
+        .await
+        .wrap_err("failed to read response")?;
+    Ok(response)
+}
@@ -0,0 +1,504 @@
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{Context, eyre};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, mpsc},
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    ACCEPT_ERROR_BACKOFF,
+    config::Iperf3Config,
+    payload::PayloadStream,
+    privacy,
+    session::{AppState, TestResult, TestSource},
+    telemetry,
+    utils::bps_to_string,
+};
+
+const COOKIE_SIZE: usize = 37;
+const DEFAULT_BLOCK_SIZE: usize = 128 * 1024;
+const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+const MAX_STREAMS: usize = 128;
+const MAX_JSON_SIZE: u32 = 1024 * 1024;
+static HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TEST_START: i8 = 1;
+const TEST_RUNNING: i8 = 2;
+const TEST_END: i8 = 4;
+const PARAM_EXCHANGE: i8 = 9;
+const CREATE_STREAMS: i8 = 10;
+const CLIENT_TERMINATE: i8 = 12;
+const EXCHANGE_RESULTS: i8 = 13;
+const DISPLAY_RESULTS: i8 = 14;
+const IPERF_DONE: i8 = 16;
+const ACCESS_DENIED: i8 = -1;
+
+type Cookie = [u8; COOKIE_SIZE];
+
+#[derive(Deserialize)]
+struct TestParams {
+    #[serde(default)]
+    tcp: bool,
+    #[serde(default)]
+    udp: bool,
+    #[serde(default)]
+    reverse: bool,
+    #[serde(default)]
+    bidirectional: bool,
+    #[serde(default)]
+    time: u64,
+    #[serde(default)]
+    omit: u64,
+    #[serde(default = "default_parallel")]
+    parallel: usize,
+    len: Option<usize>,
+}
+
+fn default_parallel() -> usize {
+    1
+}
+
+#[derive(Serialize)]
+struct StreamResults {
+    id: usize,
+    bytes: u64,
+    retransmits: i64,
+    jitter: f64,
+    errors: u64,
+    omitted_errors: u64,
+    packets: u64,
+    omitted_packets: u64,
+    start_time: f64,
+    end_time: f64,
+}
+
+#[derive(Serialize)]
+struct TestResults {
+    cpu_util_total: f64,
+    cpu_util_user: f64,
+    cpu_util_system: f64,
+    sender_has_retransmits: i64,
+    streams: Vec<StreamResults>,
+}
+
+struct RunningTest {
+    cookie: Cookie,
+    streams: mpsc::Sender<TcpStream>,
+}
+
+#[derive(Clone)]
+struct Iperf3Server {
+    state: AppState,
+    max_duration: Duration,
+    running: Arc<Mutex<Option<RunningTest>>>,
+    busy: Arc<AtomicBool>,
+}
+
+pub(crate) async fn serve(config: &Iperf3Config, state: AppState) -> color_eyre::Result<()> {
+    let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, config.port))
+        .await
+        .wrap_err_with(|| format!("failed to listen on iperf3 port {}", config.port))?;
+    info!(port = config.port, "Starting iperf3 server...");
+    let server = Iperf3Server::new(state, Duration::from_secs(config.max_duration));
+    tokio::spawn(server.accept_loop(listener));
+    Ok(())
+}
+
+impl Iperf3Server {
+    fn new(state: AppState, max_duration: Duration) -> Self {
+        Iperf3Server {
+            state,
+            max_duration,
+            running: Arc::default(),
+            busy: Arc::default(),
+        }
+    }
+
+    async fn accept_loop(self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((tcp_stream, addr)) => {
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(error) = server.handle_connection(tcp_stream, addr).await {
+                            let addr = privacy::anonymize(addr.ip());
+                            warn!(addr, %error, "iperf3 test failed.");
+                        }
+                    });
+                }
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+                    ) =>
+                {
+                    debug!(%error, "iperf3 connection aborted.")
+                }
+                Err(error) => {
+                    warn!(%error, "Failed to accept iperf3 connection.");
+                    sleep(ACCEPT_ERROR_BACKOFF).await;
+                }
+            }
+        }
+    }
+
+    async fn handle_connection(
+        &self,
+        mut tcp_stream: TcpStream,
+        addr: SocketAddr,
+    ) -> color_eyre::Result<()> {
+        let mut cookie = [0u8; COOKIE_SIZE];
+        timeout(HANDSHAKE_TIMEOUT, tcp_stream.read_exact(&mut cookie))
+            .await
+            .wrap_err("timed out reading cookie")??;
+        if let Some(running) = self.running.lock().await.as_ref()
+            && running.cookie == cookie
+        {
+            let _ = running.streams.send(tcp_stream).await;
+            return Ok(());
+        }
+        if self.busy.swap(true, Ordering::AcqRel) {
+            write_state(&mut tcp_stream, ACCESS_DENIED).await?;
+            return Ok(());
+        }
+        let result = self.run_test(tcp_stream, addr, cookie).await;
+        *self.running.lock().await = None;
+        self.busy.store(false, Ordering::Release);
+        result
+    }
+
+    async fn run_test(
+        &self,
+        mut control: TcpStream,
+        addr: SocketAddr,
+        cookie: Cookie,
+    ) -> color_eyre::Result<()> {
+        write_state(&mut control, PARAM_EXCHANGE).await?;
+        let params: TestParams = timeout(HANDSHAKE_TIMEOUT, read_json(&mut control))
+            .await
+            .wrap_err("timed out reading parameters")??;
+        if params.udp
+            || !params.tcp
+            || params.bidirectional
+            || params.parallel == 0
+            || params.parallel > MAX_STREAMS
+            || params
+                .time
+                .checked_add(params.omit)
+                .is_none_or(|seconds| Duration::from_secs(seconds) > self.max_duration)
+        {
+            write_state(&mut control, ACCESS_DENIED).await?;
+            return Err(eyre!("unsupported test parameters"));
+        }
+        let block_size = params
+            .len
+            .unwrap_or(DEFAULT_BLOCK_SIZE)
+            .clamp(1, MAX_BLOCK_SIZE);
+
+        let (tx, mut rx) = mpsc::channel(params.parallel);
+        *self.running.lock().await = Some(RunningTest {
+            cookie,
+            streams: tx,
+        });
+        write_state(&mut control, CREATE_STREAMS).await?;
+        let mut streams = Vec::with_capacity(params.parallel);
+        while streams.len() < params.parallel {
+            let stream = timeout(HANDSHAKE_TIMEOUT, rx.recv())
+                .await
+                .wrap_err("timed out waiting for streams")?
+                .ok_or_else(|| eyre!("stream channel closed"))?;
+            streams.push(stream);
+        }
+        *self.running.lock().await = None;
+
+        info!(
+            addr = privacy::anonymize(addr.ip()),
+            reverse = params.reverse,
+            parallel = params.parallel,
+            time = params.time,
+            "Starting iperf3 test."
+        );
+        write_state(&mut control, TEST_START).await?;
+        write_state(&mut control, TEST_RUNNING).await?;
+        let start = Instant::now();
+        let counters: Vec<_> = (0..streams.len())
+            .map(|_| Arc::new(AtomicU64::new(0)))
+            .collect();
+        let mut tasks = JoinSet::new();
+        for (stream, counter) in streams.into_iter().zip(counters.iter().cloned()) {
+            if params.reverse {
+                tasks.spawn(send_stream(stream, counter, block_size));
+            } else {
+                tasks.spawn(receive_stream(stream, counter, block_size));
+            }
+        }
+
+        let end_state = timeout(
+            self.max_duration + HANDSHAKE_TIMEOUT,
+            read_state(&mut control),
+        )
+        .await;
+        let end_time = start.elapsed().as_secs_f64();
+        tasks.abort_all();
+        match end_state {
+            Ok(Ok(TEST_END)) => (),
+            Ok(Ok(CLIENT_TERMINATE)) => return Err(eyre!("client terminated the test")),
+            Ok(Ok(state)) => return Err(eyre!("unexpected state {state}")),
+            Ok(Err(error)) => return Err(error),
+            Err(_) => return Err(eyre!("timed out waiting for test end")),
+        }
+
+        write_state(&mut control, EXCHANGE_RESULTS).await?;
+        let _: serde_json::Value = timeout(HANDSHAKE_TIMEOUT, read_json(&mut control))
+            .await
+            .wrap_err("timed out reading client results")??;
+        let bytes: Vec<u64> = counters
+            .iter()
+            .map(|counter| counter.load(Ordering::Acquire))
+            .collect();
+        let results = TestResults {
+            cpu_util_total: 0.0,
+            cpu_util_user: 0.0,
+            cpu_util_system: 0.0,
+            sender_has_retransmits: 0,
+            streams: bytes
+                .iter()
+                .enumerate()
+                .map(|(i, &bytes)| StreamResults {
+                    id: if i == 0 { 1 } else { i + 2 },
+                    bytes,
+                    retransmits: 0,
+                    jitter: 0.0,
+                    errors: 0,
+                    omitted_errors: 0,
+                    packets: 0,
+                    omitted_packets: 0,
+                    start_time: 0.0,
+                    end_time,
+                })
+                .collect(),
+        };
+        write_json(&mut control, &results).await?;
+        write_state(&mut control, DISPLAY_RESULTS).await?;
+        if let Ok(Ok(state)) = timeout(HANDSHAKE_TIMEOUT, read_state(&mut control)).await
+            && state != IPERF_DONE
+        {
+            warn!(
+                addr = privacy::anonymize(addr.ip()),
+                state, "Unexpected iperf3 state after results."
+            );
+        }
+
+        let total: u64 = bytes.iter().sum();
+        let bps = (total * 8) as f64 / end_time;
+        let id = Uuid::new_v4();
+        info!(
+            %id,
+            addr = privacy::anonymize(addr.ip()),
+            reverse = params.reverse,
+            bytes = total,
+            bps,
+            speed = bps_to_string(bps),
+            "Finished iperf3 test."
+        );
+        if params.reverse {
+            telemetry::record_download(TestSource::Iperf3, bps, None);
+            self.state
+                .counters
+                .downloads
+                .fetch_add(1, Ordering::Relaxed);
+        } else {
+            telemetry::record_upload(TestSource::Iperf3, bps);
+            self.state.counters.uploads.fetch_add(1, Ordering::Relaxed);
+        }
+        self.state.results.insert(
+            id,
+            TestResult {
+                source: TestSource::Iperf3,
+                profile: None,
+                download_bps: params.reverse.then_some(bps),
+                latency: None,
+                upload_bps: (!params.reverse).then_some(bps),
+                upload_overall_bps: (!params.reverse).then_some(bps),
+                possibly_proxied: false,
+                client: Some(privacy::anonymize(addr.ip())),
+                network: self.state.lookup_network(addr.ip()),
+                finished: Instant::now(),
+                span: None,
+            },
+        );
+        Ok(())
+    }
+}
+
+async fn send_stream(mut stream: TcpStream, counter: Arc<AtomicU64>, block_size: usize) {
+    let mut payload = PayloadStream::new(usize::MAX);
+    while let Some(chunk) = payload.next_chunk(block_size) {
+        match stream.write(&chunk).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                counter.fetch_add(n as u64, Ordering::AcqRel);
+            }
+        }
+    }
+}
+
+async fn receive_stream(mut stream: TcpStream, counter: Arc<AtomicU64>, block_size: usize) {
+    let mut buffer = vec![0u8; block_size];
+    loop {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                counter.fetch_add(n as u64, Ordering::AcqRel);
+            }
+        }
+    }
+}
+
+async fn write_state(tcp_stream: &mut TcpStream, state: i8) -> color_eyre::Result<()> {
+    tcp_stream
+        .write_i8(state)
+        .await
+        .wrap_err("failed to write state")
+}
+
+async fn read_state(tcp_stream: &mut TcpStream) -> color_eyre::Result<i8> {
+    tcp_stream.read_i8().await.wrap_err("failed to read state")
+}
+
+async fn read_json<T: for<'de> Deserialize<'de>>(
+    tcp_stream: &mut TcpStream,
+) -> color_eyre::Result<T> {
+    let size = tcp_stream.read_u32().await?;
+    if size > MAX_JSON_SIZE {
+        return Err(eyre!("JSON message too large ({size} bytes)"));
+    }
+    let mut buffer = vec![0u8; size as usize];
+    tcp_stream.read_exact(&mut buffer).await?;
+    serde_json::from_slice(&buffer).wrap_err("invalid JSON message")
+}
+
+async fn write_json<T: Serialize>(tcp_stream: &mut TcpStream, value: &T) -> color_eyre::Result<()> {
+    let buffer = serde_json::to_vec(value)?;
+    tcp_stream.write_u32(buffer.len() as u32).await?;
+    tcp_stream.write_all(&buffer).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    static COOKIE: &Cookie = b"0123456789abcdefghijklmnopqrstuvwxyz\0";
+
+    async fn server() -> (Iperf3Server, SocketAddr) {
+        let server = Iperf3Server::new(AppState::for_tests(), Duration::from_secs(10));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.clone().accept_loop(listener));
+        (server, addr)
+    }
+
+    /// Opens the control connection and sends `params`, returning the state
+    /// that the server answers with.
+    async fn exchange_params(addr: SocketAddr, params: serde_json::Value) -> (TcpStream, i8) {
+        let mut control = TcpStream::connect(addr).await.unwrap();
+        control.write_all(COOKIE).await.unwrap();
+        assert_eq!(read_state(&mut control).await.unwrap(), PARAM_EXCHANGE);
+        write_json(&mut control, &params).await.unwrap();
+        let state = read_state(&mut control).await.unwrap();
+        (control, state)
+    }
+
+    async fn wait_idle(server: &Iperf3Server) {
+        while server.busy.load(Ordering::Acquire) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn reverse_test() {
+        let (server, addr) = server().await;
+        let (mut control, state) = exchange_params(
+            addr,
+            json!({"tcp": true, "reverse": true, "time": 1, "parallel": 1, "len": 1024}),
+        )
+        .await;
+        assert_eq!(state, CREATE_STREAMS);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(COOKIE).await.unwrap();
+        assert_eq!(read_state(&mut control).await.unwrap(), TEST_START);
+        assert_eq!(read_state(&mut control).await.unwrap(), TEST_RUNNING);
+        let mut received = vec![0u8; 64 * 1024];
+        stream.read_exact(&mut received).await.unwrap();
+
+        write_state(&mut control, TEST_END).await.unwrap();
+        assert_eq!(read_state(&mut control).await.unwrap(), EXCHANGE_RESULTS);
+        write_json(&mut control, &json!({})).await.unwrap();
+        let results: serde_json::Value = read_json(&mut control).await.unwrap();
+        assert_eq!(results["streams"][0]["id"], 1);
+        assert!(results["streams"][0]["bytes"].as_u64().unwrap() >= received.len() as u64);
+        assert_eq!(read_state(&mut control).await.unwrap(), DISPLAY_RESULTS);
+        write_state(&mut control, IPERF_DONE).await.unwrap();
+
+        // The server closes the control connection once the result is stored.
+        let mut rest = vec![];
+        control.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        let result = server.state.results.iter().next().unwrap();
+        assert!(matches!(result.source, TestSource::Iperf3));
+        assert!(result.download_bps.is_some_and(|bps| bps > 0.0));
+        assert_eq!(result.upload_bps, None);
+    }
+
+    #[tokio::test]
+    async fn denied_params() {
+        let (server, addr) = server().await;
+        let cases = [
+            ("UDP", json!({"tcp": false, "udp": true, "time": 1})),
+            (
+                "bidirectional",
+                json!({"tcp": true, "bidirectional": true, "time": 1}),
+            ),
+            ("no streams", json!({"tcp": true, "time": 1, "parallel": 0})),
+            (
+                "too many streams",
+                json!({"tcp": true, "time": 1, "parallel": 129}),
+            ),
+            ("too long", json!({"tcp": true, "time": 8, "omit": 3})),
+            (
+                "overflowing duration",
+                json!({"tcp": true, "time": u64::MAX, "omit": 1}),
+            ),
+        ];
+        for (name, params) in cases {
+            let (_, state) = exchange_params(addr, params).await;
+            assert_eq!(state, ACCESS_DENIED, "{name}");
+            wait_idle(&server).await;
+        }
+
+        let (_control, state) = exchange_params(addr, json!({"tcp": true, "time": 10})).await;
+        assert_eq!(state, CREATE_STREAMS);
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second.write_all(&[b'x'; COOKIE_SIZE]).await.unwrap();
+        assert_eq!(read_state(&mut second).await.unwrap(), ACCESS_DENIED);
+    }
+}
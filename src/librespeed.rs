@@ -0,0 +1,286 @@
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    Router,
+    body::Body,
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, HeaderName, StatusCode, header},
+    response::{IntoResponse, Json},
+    routing::get,
+};
+use bytes::Bytes;
+use http_body::{Body as HttpBody, Frame};
+use http_body_util::{BodyExt, Limited};
+use serde::{Deserialize, Serialize};
+
+use crate::{payload::PayloadStream, session::AppState, utils::client_ip};
+
+static CHUNK_SIZE: usize = 1_048_576;
+static DEFAULT_CHUNKS: usize = 4;
+static MAX_CHUNKS: usize = 1_024;
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/empty", get(empty).post(empty).options(preflight))
+        .route("/empty.php", get(empty).post(empty).options(preflight))
+        .route("/garbage", get(garbage).options(preflight))
+        .route("/garbage.php", get(garbage).options(preflight))
+        .route("/getIP", get(get_ip).options(preflight))
+        .route("/getIP.php", get(get_ip).options(preflight))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CorsQuery {
+    cors: Option<String>,
+}
+
+fn response_headers(cors: bool) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CACHE_CONTROL,
+        "no-store, no-cache, no-transform, must-revalidate, max-age=0, s-maxage=0"
+            .parse()
+            .unwrap(),
+    );
+    headers.insert(header::PRAGMA, "no-cache".parse().unwrap());
+    headers.insert(header::CONTENT_ENCODING, "identity".parse().unwrap());
+    if cors {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            "GET, POST".parse().unwrap(),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            "Content-Encoding, Content-Type".parse().unwrap(),
+        );
+    }
+    headers
+}
+
+/// Cross-origin uploads are not simple requests, so browsers check the CORS
+/// headers first.
+pub(crate) async fn preflight(Query(CorsQuery { cors }): Query<CorsQuery>) -> impl IntoResponse {
+    (StatusCode::NO_CONTENT, response_headers(cors.is_some()))
+}
+
+pub(crate) async fn empty(
+    State(state): State<AppState>,
+    Query(CorsQuery { cors }): Query<CorsQuery>,
+    body: Body,
+) -> impl IntoResponse {
+    let mut body = Limited::new(body, state.max_upload_bytes);
+    while let Some(frame) = body.frame().await {
+        if frame.is_err() {
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+    }
+    (
+        response_headers(cors.is_some()),
+        [(header::CONNECTION, "keep-alive")],
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GarbageQuery {
+    #[serde(rename = "ckSize")]
+    chunk_count: Option<usize>,
+    cors: Option<String>,
+}
+
+pub(crate) async fn garbage(
+    Query(GarbageQuery { chunk_count, cors }): Query<GarbageQuery>,
+) -> impl IntoResponse {
+    let chunk_count = chunk_count.unwrap_or(DEFAULT_CHUNKS).min(MAX_CHUNKS);
+    (
+        response_headers(cors.is_some()),
+        [
+            (header::CONTENT_TYPE, "application/octet-stream"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=random.dat",
+            ),
+            (
+                HeaderName::from_static("content-description"),
+                "File Transfer",
+            ),
+        ],
+        Body::new(GarbageBody {
+            payload: PayloadStream::new(chunk_count * CHUNK_SIZE),
+        }),
+    )
+}
+
+pub(crate) struct GarbageBody {
+    payload: PayloadStream,
+}
+
+impl HttpBody for GarbageBody {
+    type Data = Bytes;
+
+    type Error = color_eyre::Report;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Poll::Ready(
+            self.payload
+                .next_chunk(CHUNK_SIZE)
+                .map(|chunk| Ok(Frame::data(chunk))),
+        )
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        http_body::SizeHint::with_exact(self.payload.remaining() as u64)
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct GetIpResponse {
+    #[serde(rename = "processedString")]
+    processed_string: String,
+    #[serde(rename = "rawIspInfo")]
+    raw_isp_info: String,
+}
+
+pub(crate) async fn get_ip(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(CorsQuery { cors }): Query<CorsQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    (
+        response_headers(cors.is_some()),
+        Json(GetIpResponse {
+            processed_string: client_ip(&headers, addr).to_string(),
+            raw_isp_info: String::new(),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use axum::{
+        http::{Method, Request},
+        response::Response,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Upload of `remaining` chunks, counting those read by the server.
+    struct UploadBody {
+        remaining: usize,
+        read: Arc<AtomicUsize>,
+    }
+
+    impl HttpBody for UploadBody {
+        type Data = Bytes;
+
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            if self.remaining == 0 {
+                return Poll::Ready(None);
+            }
+            self.remaining -= 1;
+            self.read.fetch_add(1, Ordering::Relaxed);
+            Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(&[0; 1024])))))
+        }
+    }
+
+    async fn request(method: Method, uri: &str, body: Body) -> Response {
+        router()
+            .with_state(AppState::for_tests())
+            .oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .body(body)
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn garbage_size() {
+        let cases = [
+            ("/garbage.php", DEFAULT_CHUNKS),
+            ("/garbage.php?ckSize=1", 1),
+            ("/garbage?ckSize=100", 100),
+            ("/garbage.php?ckSize=1000000", MAX_CHUNKS),
+        ];
+        for (uri, chunks) in cases {
+            let response = request(Method::GET, uri, Body::empty()).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+            assert_eq!(
+                response.body().size_hint().exact(),
+                Some((chunks * CHUNK_SIZE) as u64),
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_drains_upload() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let body = Body::new(UploadBody {
+            remaining: 100,
+            read: Arc::clone(&read),
+        });
+        let response = request(Method::POST, "/empty.php", body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read.load(Ordering::Relaxed), 100);
+
+        // The test state allows uploads of up to 1 MB.
+        let body = Body::new(UploadBody {
+            remaining: 2_000,
+            read: Arc::new(AtomicUsize::new(0)),
+        });
+        let response = request(Method::POST, "/empty.php", body).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn preflight() {
+        for uri in ["/empty.php?cors", "/garbage.php?cors", "/getIP?cors"] {
+            let response = request(Method::OPTIONS, uri, Body::empty()).await;
+            assert!(response.status().is_success(), "{uri}");
+            let headers = response.headers();
+            assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*", "{uri}");
+            assert_eq!(
+                headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+                "GET, POST",
+                "{uri}"
+            );
+            assert_eq!(
+                headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+                "Content-Encoding, Content-Type",
+                "{uri}"
+            );
+        }
+        let response = request(Method::OPTIONS, "/empty.php", Body::empty()).await;
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+    }
+}
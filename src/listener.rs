@@ -0,0 +1,700 @@
+use std::{
+    convert::Infallible,
+    fs,
+    io::IoSlice,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::{fd::OwnedFd, unix::fs::FileTypeExt},
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use ahash::RandomState;
+use axum::{Extension, Router, body::Body, extract::ConnectInfo};
+use color_eyre::eyre::{Context as _, eyre};
+use dashmap::DashMap;
+use http_body_util::BodyExt;
+use hyper::service::service_fn;
+use hyper_util::{rt::TokioIo, server::graceful::Watcher};
+use rustls::{
+    RootCertStore, ServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::{Semaphore, SemaphorePermit},
+    time::{Instant, sleep_until, timeout},
+};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::debug;
+
+use crate::{
+    config::{Config, ListenerConfig, TlsConfig},
+    connection::{ConnectionSocket, ConnectionWrites, TlsInfo},
+    privacy, proxy_protocol,
+};
+
+static HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// Per-listener settings applied to every accepted connection.
+pub(crate) struct ListenerSettings {
+    proxy_protocol: bool,
+    tls: Option<TlsAcceptor>,
+}
+
+/// Timeouts and connection limits shared by all listeners.
+pub(crate) struct ConnectionLimits {
+    header_read_timeout: Duration,
+    keep_alive_timeout: Duration,
+    connections: Option<Semaphore>,
+    max_connections_per_ip: Option<usize>,
+    connections_per_ip: DashMap<IpAddr, usize, RandomState>,
+}
+
+impl ConnectionLimits {
+    pub(crate) fn new(config: &Config) -> Self {
+        ConnectionLimits {
+            header_read_timeout: Duration::from_secs(config.header_read_timeout),
+            keep_alive_timeout: Duration::from_secs(config.keep_alive_timeout),
+            connections: config.max_connections.map(Semaphore::new),
+            max_connections_per_ip: config.max_connections_per_ip,
+            connections_per_ip: DashMap::default(),
+        }
+    }
+
+    /// Reserves a connection slot, or `None` if the server is at capacity.
+    fn acquire(&self) -> Option<Option<SemaphorePermit<'_>>> {
+        match &self.connections {
+            Some(connections) => connections.try_acquire().ok().map(Some),
+            None => Some(None),
+        }
+    }
+
+    /// Counts a connection from `ip`, or `None` if it has too many already.
+    fn acquire_ip(&self, ip: IpAddr) -> Option<IpGuard<'_>> {
+        let ip = ip.to_canonical();
+        if let Some(max_connections_per_ip) = self.max_connections_per_ip {
+            let mut connections = self.connections_per_ip.entry(ip).or_default();
+            if *connections >= max_connections_per_ip {
+                return None;
+            }
+            *connections += 1;
+        }
+        Some(IpGuard { limits: self, ip })
+    }
+}
+
+struct IpGuard<'a> {
+    limits: &'a ConnectionLimits,
+    ip: IpAddr,
+}
+
+impl Drop for IpGuard<'_> {
+    fn drop(&mut self) {
+        if self.limits.max_connections_per_ip.is_some() {
+            self.limits
+                .connections_per_ip
+                .remove_if_mut(&self.ip, |_, connections| {
+                    *connections -= 1;
+                    *connections == 0
+                });
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ActivityState {
+    requests: usize,
+    served: bool,
+    idle_since: Instant,
+    /// When the first byte of the next request head arrived.
+    head_since: Option<Instant>,
+}
+
+/// Tracks the requests in progress on a connection, to close it once it has
+/// been idle or taken too long to send a request head.
+///
+/// hyper's own header read timeout also starts when a connection becomes
+/// idle, which would cap the keep-alive timeout.
+struct Activity(Mutex<ActivityState>);
+
+impl Activity {
+    fn new() -> Self {
+        Activity(Mutex::new(ActivityState {
+            requests: 0,
+            served: false,
+            idle_since: Instant::now(),
+            head_since: None,
+        }))
+    }
+
+    fn start(self: &Arc<Self>) -> ActivityGuard {
+        let mut state = self.0.lock().unwrap();
+        state.requests += 1;
+        state.served = true;
+        state.head_since = None;
+        ActivityGuard(Arc::clone(self))
+    }
+
+    fn received(&self) {
+        let mut state = self.0.lock().unwrap();
+        if state.requests == 0 && state.head_since.is_none() {
+            state.head_since = Some(Instant::now());
+        }
+    }
+
+    /// Resolves once the connection has timed out without a request in progress.
+    async fn timed_out(&self, limits: &ConnectionLimits) {
+        loop {
+            let state = *self.0.lock().unwrap();
+            let deadline = match state.head_since {
+                _ if state.requests > 0 => Instant::now() + limits.keep_alive_timeout,
+                Some(head_since) => head_since + limits.header_read_timeout,
+                None if !state.served => state.idle_since + limits.header_read_timeout,
+                None => state.idle_since + limits.keep_alive_timeout,
+            };
+            if deadline <= Instant::now() {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Marks a request as in progress until its response body is dropped.
+struct ActivityGuard(Arc<Activity>);
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        let mut state = self.0.0.lock().unwrap();
+        state.requests -= 1;
+        if state.requests == 0 {
+            state.idle_since = Instant::now();
+        }
+    }
+}
+
+/// Reports received bytes to the connection's [`Activity`] and written ones to
+/// its [`ConnectionWrites`], and releases the [`ConnectionSocket`] before the
+/// stream is closed.
+struct ActivityIo<I> {
+    inner: I,
+    activity: Arc<Activity>,
+    writes: ConnectionWrites,
+    socket: Option<ConnectionSocket>,
+}
+
+impl<I> Drop for ActivityIo<I> {
+    fn drop(&mut self) {
+        if let Some(socket) = &self.socket {
+            socket.release();
+        }
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for ActivityIo<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = ready!(Pin::new(&mut self.inner).poll_read(cx, buf));
+        if buf.filled().len() > filled {
+            self.activity.received();
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for ActivityIo<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = ready!(Pin::new(&mut self.inner).poll_write(cx, buf));
+        if let Ok(size) = result {
+            self.writes.add(size);
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = ready!(Pin::new(&mut self.inner).poll_write_vectored(cx, bufs));
+        if let Ok(size) = result {
+            self.writes.add(size);
+        }
+        Poll::Ready(result)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub(crate) struct Listener {
+    socket: Socket,
+    url: String,
+    settings: Arc<ListenerSettings>,
+}
+
+pub(crate) struct Accepted {
+    stream: Stream,
+    addr: SocketAddr,
+    settings: Arc<ListenerSettings>,
+}
+
+impl Listener {
+    pub(crate) async fn bind(config: &ListenerConfig) -> color_eyre::Result<Self> {
+        let tls = config.tls.as_ref().map(tls_acceptor).transpose()?;
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let (socket, url) = match (&config.address, &config.path) {
+            (Some(address), _) => (
+                Socket::Tcp(
+                    TcpListener::bind(address)
+                        .await
+                        .wrap_err_with(|| format!("failed to listen on {address}"))?,
+                ),
+                format!("{scheme}://{address}"),
+            ),
+            (None, Some(path)) => {
+                // Replace the socket left behind by a previous run.
+                if let Ok(metadata) = fs::symlink_metadata(path)
+                    && metadata.file_type().is_socket()
+                {
+                    fs::remove_file(path)
+                        .wrap_err_with(|| format!("failed to remove stale socket {path:?}"))?;
+                }
+                (
+                    Socket::Unix(
+                        UnixListener::bind(path)
+                            .wrap_err_with(|| format!("failed to listen on {path:?}"))?,
+                    ),
+                    format!("{scheme}+unix://{}", path.display()),
+                )
+            }
+            (None, None) => return Err(eyre!("listener has no address or path")),
+        };
+        Ok(Listener {
+            socket,
+            url,
+            settings: Arc::new(ListenerSettings {
+                proxy_protocol: config.proxy_protocol,
+                tls,
+            }),
+        })
+    }
+
+    /// Wraps a listening socket inherited from the service manager, applying
+    /// the TLS and PROXY settings of `config` but ignoring its address.
+    pub(crate) fn from_fd(fd: OwnedFd, config: &ListenerConfig) -> color_eyre::Result<Self> {
+        let tls = config.tls.as_ref().map(tls_acceptor).transpose()?;
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let listener = std::os::unix::net::UnixListener::from(fd);
+        let (socket, url) = match listener.local_addr() {
+            Ok(addr) => {
+                listener
+                    .set_nonblocking(true)
+                    .wrap_err("failed to set inherited socket as non-blocking")?;
+                let path = addr.as_pathname().unwrap_or(Path::new("?"));
+                (
+                    Socket::Unix(
+                        UnixListener::from_std(listener)
+                            .wrap_err("failed to register inherited socket")?,
+                    ),
+                    format!("{scheme}+unix://{}", path.display()),
+                )
+            }
+            // Not a Unix socket, so it must be a TCP one.
+            Err(_) => {
+                let listener = std::net::TcpListener::from(OwnedFd::from(listener));
+                let addr = listener
+                    .local_addr()
+                    .wrap_err("inherited socket is neither TCP nor Unix")?;
+                listener
+                    .set_nonblocking(true)
+                    .wrap_err("failed to set inherited socket as non-blocking")?;
+                (
+                    Socket::Tcp(
+                        TcpListener::from_std(listener)
+                            .wrap_err("failed to register inherited socket")?,
+                    ),
+                    format!("{scheme}://{addr}"),
+                )
+            }
+        };
+        Ok(Listener {
+            socket,
+            url,
+            settings: Arc::new(ListenerSettings {
+                proxy_protocol: config.proxy_protocol,
+                tls,
+            }),
+        })
+    }
+
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub(crate) async fn accept(&self) -> std::io::Result<Accepted> {
+        let (stream, addr) = match &self.socket {
+            Socket::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                (Stream::Tcp(stream), addr)
+            }
+            // Unix sockets have no peer address; clients behind a local proxy are
+            // identified through the PROXY header or X-Forwarded-For instead.
+            Socket::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                (Stream::Unix(stream), (Ipv4Addr::LOCALHOST, 0).into())
+            }
+        };
+        Ok(Accepted {
+            stream,
+            addr,
+            settings: Arc::clone(&self.settings),
+        })
+    }
+}
+
+fn tls_acceptor(config: &TlsConfig) -> color_eyre::Result<TlsAcceptor> {
+    let certificates = CertificateDer::pem_file_iter(&config.certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .wrap_err_with(|| format!("failed to read certificate {:?}", config.certificate))?;
+    let private_key = PrivateKeyDer::from_pem_file(&config.private_key)
+        .wrap_err_with(|| format!("failed to read private key {:?}", config.private_key))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let client_verifier = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in CertificateDer::pem_file_iter(client_ca)
+                .wrap_err_with(|| format!("failed to read client CA {client_ca:?}"))?
+            {
+                roots
+                    .add(
+                        certificate
+                            .wrap_err_with(|| format!("failed to read client CA {client_ca:?}"))?,
+                    )
+                    .wrap_err_with(|| format!("invalid client CA {client_ca:?}"))?;
+            }
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::clone(&provider))
+                .allow_unauthenticated()
+                .build()
+                .wrap_err_with(|| format!("invalid client CA {client_ca:?}"))?
+        }
+        None => WebPkiClientVerifier::no_client_auth(),
+    };
+    let mut server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .wrap_err("failed to configure TLS")?
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(certificates, private_key)
+        .wrap_err("invalid TLS certificate or private key")?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Reads the PROXY header and completes the TLS handshake if the listener
+/// requires them, then serves HTTP on the connection.
+pub(crate) async fn serve(
+    accepted: Accepted,
+    app: Router,
+    watcher: Watcher,
+    limits: Arc<ConnectionLimits>,
+) {
+    let Accepted {
+        stream,
+        addr,
+        settings,
+    } = accepted;
+    let Some(_permit) = limits.acquire() else {
+        debug!(
+            addr = privacy::anonymize(addr.ip()),
+            "Too many connections, closing."
+        );
+        return;
+    };
+    match stream {
+        Stream::Tcp(stream) => {
+            let socket = Some(ConnectionSocket::new(&stream));
+            serve_stream(stream, addr, socket, &settings, app, watcher, &limits).await
+        }
+        Stream::Unix(stream) => {
+            serve_stream(stream, addr, None, &settings, app, watcher, &limits).await
+        }
+    }
+}
+
+async fn serve_stream<I>(
+    mut stream: I,
+    mut addr: SocketAddr,
+    socket: Option<ConnectionSocket>,
+    settings: &ListenerSettings,
+    app: Router,
+    watcher: Watcher,
+    limits: &ConnectionLimits,
+) where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    // Clients of a Unix socket share a placeholder address, unless the PROXY
+    // header tells them apart.
+    let mut peer_known = socket.is_some();
+    if settings.proxy_protocol {
+        match timeout(HANDSHAKE_TIMEOUT, proxy_protocol::read_header(&mut stream)).await {
+            Ok(Ok(Some(source))) => {
+                addr = source;
+                peer_known = true;
+            }
+            Ok(Ok(None)) => (),
+            Ok(Err(error)) => {
+                debug!(addr = privacy::anonymize(addr.ip()), %error, "Invalid PROXY header.");
+                return;
+            }
+            Err(_) => return,
+        }
+    }
+    let _ip_guard = if peer_known {
+        let Some(ip_guard) = limits.acquire_ip(addr.ip()) else {
+            debug!(
+                addr = privacy::anonymize(addr.ip()),
+                "Too many connections from address, closing."
+            );
+            return;
+        };
+        Some(ip_guard)
+    } else {
+        None
+    };
+    let mut service = app.layer(Extension(ConnectInfo(addr)));
+    if let Some(socket) = &socket {
+        service = service.layer(Extension(socket.clone()));
+    }
+    match &settings.tls {
+        Some(acceptor) => match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => {
+                let service = match TlsInfo::new(stream.get_ref().1) {
+                    Some(tls) => service.layer(Extension(tls)),
+                    None => service,
+                };
+                serve_http(stream, socket, service, watcher, limits).await
+            }
+            Ok(Err(error)) => {
+                debug!(addr = privacy::anonymize(addr.ip()), %error, "TLS handshake failed.")
+            }
+            Err(_) => (),
+        },
+        None => serve_http(stream, socket, service, watcher, limits).await,
+    }
+}
+
+/// Serves HTTP/1.1 on the connection until the client closes it or it times out.
+async fn serve_http<I>(
+    stream: I,
+    socket: Option<ConnectionSocket>,
+    service: Router,
+    watcher: Watcher,
+    limits: &ConnectionLimits,
+) where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let activity = Arc::new(Activity::new());
+    let writes = ConnectionWrites::default();
+    let service = service.layer(Extension(writes.clone()));
+    let service = service_fn({
+        let activity = Arc::clone(&activity);
+        move |request| {
+            let guard = activity.start();
+            let response = service.clone().oneshot(request);
+            async move {
+                let response = response.await?;
+                // Streamed responses keep the request in progress until they end.
+                Ok::<_, Infallible>(response.map(|body| {
+                    Body::new(body.map_frame(move |frame| {
+                        let _ = &guard;
+                        frame
+                    }))
+                }))
+            }
+        }
+    });
+    let stream = ActivityIo {
+        inner: stream,
+        activity: Arc::clone(&activity),
+        writes,
+        socket,
+    };
+    let connection =
+        hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+    tokio::select! {
+        _ = watcher.watch(connection) => (),
+        _ = activity.timed_out(limits) => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use hyper_util::server::graceful::GracefulShutdown;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::advance,
+    };
+
+    use super::*;
+
+    fn limits(max_connections_per_ip: Option<usize>) -> ConnectionLimits {
+        ConnectionLimits {
+            header_read_timeout: Duration::from_secs(10),
+            keep_alive_timeout: Duration::from_secs(60),
+            connections: None,
+            max_connections_per_ip,
+            connections_per_ip: DashMap::default(),
+        }
+    }
+
+    /// Time until the connection times out.
+    async fn time_out(activity: &Activity, limits: &ConnectionLimits) -> Duration {
+        let start = Instant::now();
+        activity.timed_out(limits).await;
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn header_read_deadline() {
+        let limits = limits(None);
+        let activity = Arc::new(Activity::new());
+        assert_eq!(
+            time_out(&activity, &limits).await,
+            limits.header_read_timeout,
+            "first request"
+        );
+
+        // A keep-alive connection gets the header read timeout from the first
+        // byte of the next request.
+        let activity = Arc::new(Activity::new());
+        drop(activity.start());
+        advance(Duration::from_secs(30)).await;
+        activity.received();
+        advance(Duration::from_secs(5)).await;
+        activity.received();
+        assert_eq!(
+            time_out(&activity, &limits).await,
+            Duration::from_secs(5),
+            "next request"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_alive_deadline() {
+        let limits = limits(None);
+        let activity = Arc::new(Activity::new());
+        let guard = activity.start();
+        assert!(
+            timeout(Duration::from_secs(3_600), activity.timed_out(&limits))
+                .await
+                .is_err(),
+            "request in progress"
+        );
+
+        drop(guard);
+        advance(Duration::from_secs(20)).await;
+        assert_eq!(
+            time_out(&activity, &limits).await,
+            Duration::from_secs(40),
+            "idle"
+        );
+
+        // The keep-alive timeout restarts after every request.
+        let activity = Arc::new(Activity::new());
+        drop(activity.start());
+        advance(Duration::from_secs(50)).await;
+        drop(activity.start());
+        assert_eq!(
+            time_out(&activity, &limits).await,
+            limits.keep_alive_timeout
+        );
+    }
+
+    /// Sends a request over a Unix socket connection, and returns the response
+    /// status line, or an empty string if it was closed, with the open client.
+    async fn unix_request(
+        limits: &Arc<ConnectionLimits>,
+        graceful: &GracefulShutdown,
+        proxy_header: &str,
+    ) -> (String, UnixStream) {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let settings = Arc::new(ListenerSettings {
+            proxy_protocol: true,
+            tls: None,
+        });
+        let app = Router::new().route("/", get(async || "ok"));
+        let watcher = graceful.watcher();
+        tokio::spawn({
+            let limits = Arc::clone(limits);
+            async move {
+                let addr = (Ipv4Addr::LOCALHOST, 0).into();
+                serve_stream(server, addr, None, &settings, app, watcher, &limits).await
+            }
+        });
+        client
+            .write_all(
+                format!("{proxy_header}GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = [0u8; 15];
+        let size = client.read(&mut response).await.unwrap_or(0);
+        (
+            String::from_utf8_lossy(&response[..size]).into_owned(),
+            client,
+        )
+    }
+
+    #[tokio::test]
+    async fn unix_connections_per_ip() {
+        let limits = Arc::new(limits(Some(1)));
+        let graceful = GracefulShutdown::new();
+        let mut clients = vec![];
+        for _ in 0..3 {
+            let (status, client) = unix_request(&limits, &graceful, "PROXY UNKNOWN\r\n").await;
+            assert_eq!(status, "HTTP/1.1 200 OK", "without an address");
+            clients.push(client);
+        }
+        assert!(limits.connections_per_ip.is_empty());
+
+        let proxy_header = "PROXY TCP4 192.0.2.1 198.51.100.2 1234 80\r\n";
+        let (status, _client) = unix_request(&limits, &graceful, proxy_header).await;
+        assert_eq!(status, "HTTP/1.1 200 OK", "first from the PROXY address");
+        let (status, _) = unix_request(&limits, &graceful, proxy_header).await;
+        assert_eq!(status, "", "second from the PROXY address");
+    }
+}
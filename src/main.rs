@@ -1,117 +1,322 @@
-use std::{net::Ipv6Addr, sync::Arc};
+use std::{io, os::fd::OwnedFd, sync::Arc, time::Duration};
 
+use askama::Template;
 use axum::{
     Extension, Router,
-    extract::{ConnectInfo, DefaultBodyLimit},
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    routing::{get, post, put},
 };
 use bytes::Bytes;
-use color_eyre::eyre::{Context, eyre};
-use hyper_util::{rt::TokioIo, service::TowerToHyperService};
-use image::{ExtendedColorType, codecs::bmp::BmpEncoder};
-use rand::RngCore;
-use tracing::{error, info};
+use color_eyre::eyre::Context;
+use hyper_util::server::graceful::GracefulShutdown;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::mpsc,
+    task::JoinSet,
+    time::sleep,
+};
+use tracing::{debug, info, warn};
 use tracing_subscriber::{Layer, layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::{
-    download::RANDOM_BITMAP,
-    routes::{download, favicon, index, privacy, results, start, upload},
+    auth::{AuthLayer, Authenticator},
+    config::{Config, ListenerConfig, LogFormat, Profile},
+    geoip::GeoIp,
+    listener::{ConnectionLimits, Listener},
+    payload::PayloadFormat,
+    routes::{cancel, download, favicon, index, privacy, results, start, upload, upload_raw},
     session::AppState,
-    utils::bytes_to_string,
+    telemetry::Telemetry,
+    templates::{ExpiredTemplate, RestartingTemplate},
 };
 
+mod admin;
+mod api;
+mod auth;
+mod basic;
+mod config;
+mod connection;
 mod download;
+mod geoip;
+mod health;
+mod iperf3;
+mod librespeed;
+mod listener;
+mod measure;
+mod payload;
+mod privacy;
+mod proxy_protocol;
 mod routes;
 mod session;
+mod systemd;
+mod telemetry;
 mod templates;
+mod terminal;
 mod utils;
 
-#[tokio::main]
-async fn main() -> color_eyre::Result<()> {
+pub(crate) static ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
+    let activated_fds = systemd::listen_fds()?;
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .wrap_err("failed to start runtime")?
+        .block_on(run(activated_fds))
+}
+
+async fn run(activated_fds: Vec<OwnedFd>) -> color_eyre::Result<()> {
+    match std::env::args().nth(1).as_deref() {
+        Some("healthcheck") => return health::check(&Config::load()?).await,
+        Some("hash-password") => return auth::hash_password(),
+        _ => (),
+    }
 
+    let config = Config::load()?;
+    privacy::init(&config.privacy);
+
+    let telemetry = config.otlp.as_ref().map(Telemetry::init).transpose()?;
+    let log_layer = match config.log_format {
+        LogFormat::Compact => tracing_subscriber::fmt::Layer::default()
+            .compact()
+            .with_timer(tracing_subscriber::fmt::time::ChronoUtc::rfc_3339())
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::Layer::default()
+            .json()
+            .flatten_event(true)
+            .with_timer(tracing_subscriber::fmt::time::ChronoUtc::rfc_3339())
+            .boxed(),
+    };
     tracing_subscriber::registry()
         .with(
-            tracing_subscriber::fmt::Layer::default()
-                .compact()
-                .with_timer(tracing_subscriber::fmt::time::ChronoUtc::rfc_3339())
-                .with_filter(
-                    tracing_subscriber::EnvFilter::builder()
-                        .with_default_directive(tracing::level_filters::LevelFilter::INFO.into())
-                        .from_env_lossy(),
-                ),
+            log_layer.with_filter(
+                tracing_subscriber::EnvFilter::builder()
+                    .with_default_directive(tracing::level_filters::LevelFilter::INFO.into())
+                    .from_env_lossy(),
+            ),
         )
+        .with(telemetry.as_ref().map(|telemetry| {
+            tracing_opentelemetry::layer()
+                .with_tracer(telemetry.tracer())
+                .with_filter(tracing::level_filters::LevelFilter::INFO)
+        }))
         .with(tracing_error::ErrorLayer::default())
         .try_init()
         .wrap_err_with(|| "failed to initialize tracing")?;
 
-    let image_size: usize = 100_000_000;
-    let image_width: u32 = 5_000;
-    let image_height: u32 = 5_000;
-    let server_port: u16 = 3000;
-    let max_upload_size: usize = 250_000_000;
+    let shutdown_timeout = config.shutdown_timeout;
+    let session_idle_timeout = Duration::from_secs(config.session_idle_timeout);
+    let limits = Arc::new(ConnectionLimits::new(&config));
+    let max_upload_size = config.max_upload_size;
 
-    if ExtendedColorType::Rgba8.bits_per_pixel() as usize
-        * image_width as usize
-        * image_height as usize
-        != 8 * image_size
-    {
-        error!(image_size, image_width, image_height, "Invalid dimensions");
-        return Err(eyre!("Cannot initialize random data (invalid dimensions)"));
+    // Bind before the slow initialization, so that early connections are queued.
+    let mut activated_fds = activated_fds.into_iter();
+    let mut listeners = vec![];
+    for listener_config in &config.listeners {
+        listeners.push(match activated_fds.next() {
+            Some(fd) => Listener::from_fd(fd, listener_config)?,
+            None => Listener::bind(listener_config).await?,
+        });
+    }
+    for fd in activated_fds {
+        listeners.push(Listener::from_fd(fd, &ListenerConfig::default())?);
     }
 
+    let profiles: Arc<[Arc<Profile>]> = config.profiles.into_iter().map(Arc::new).collect();
+    let default_profile = profiles
+        .iter()
+        .find(|profile| profile.name == config.default_profile)
+        .cloned()
+        .unwrap();
+    let max_body_size = profiles
+        .iter()
+        .map(|profile| profile.max_upload_size)
+        .fold(max_upload_size, usize::max);
+    let max_download_size = profiles
+        .iter()
+        .map(|profile| profile.max_size)
+        .max()
+        .unwrap_or_default();
+
     info!(
-        image_size,
-        image_width, image_height, "Initializing random data..."
+        pool_size = config.payload_pool_size,
+        "Initializing random data..."
     );
-    let mut random_image = vec![];
-    let mut encoder = BmpEncoder::new(&mut random_image);
-    let mut random_data = vec![0u8; image_size];
-    rand::rng().fill_bytes(&mut random_data);
-    encoder
-        .encode(
-            &random_data[..],
-            image_width,
-            image_height,
-            ExtendedColorType::Rgba8,
-        )
-        .wrap_err_with(|| "failed to encode bitmap")?;
-    drop(random_data);
-    RANDOM_BITMAP
-        .set(Bytes::from_static(random_image.leak()))
-        .unwrap();
+    payload::init(config.payload_pool_size);
+
+    let state = AppState {
+        conn: Arc::default(),
+        results: Arc::default(),
+        profiles,
+        default_profile,
+        max_upload_bytes: max_upload_size,
+        max_download_bytes: max_download_size,
+        payload_format: config.payload_format,
+        draining: Arc::default(),
+        max_sessions: config.max_sessions,
+        geoip: config
+            .geoip
+            .as_ref()
+            .map(GeoIp::open)
+            .transpose()?
+            .map(Arc::new),
+        node_name: config.node_name.unwrap_or_default().into(),
+        counters: Arc::default(),
+    };
+    if telemetry.is_some() {
+        telemetry::observe_sessions(state.clone());
+    }
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                state.purge_results();
+            }
+        }
+    });
+    tokio::spawn({
+        let state = state.clone();
+        let html = Bytes::from(ExpiredTemplate {}.render().unwrap());
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                state.expire_idle(session_idle_timeout, &html);
+            }
+        }
+    });
 
-    let app = Router::new()
+    if let Some(iperf3_config) = &config.iperf3 {
+        iperf3::serve(iperf3_config, state.clone()).await?;
+    }
+
+    let mut app = Router::new()
         .route("/", get(index))
         .route("/privacy", get(privacy))
         .route("/favicon.svg", get(favicon))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
         .route("/empty.jpg", get(async || {}))
         .route("/{id}/start.jpg", get(start))
-        .route("/{id}/download.bmp", get(download))
+        .route("/{id}/cancel.jpg", get(cancel))
+        .route(
+            "/{id}/download.bmp",
+            get(download).layer(Extension(PayloadFormat::Bitmap)),
+        )
+        .route(
+            "/{id}/download.png",
+            get(download).layer(Extension(PayloadFormat::Png)),
+        )
+        .route(
+            "/{id}/download.bin",
+            get(download).layer(Extension(PayloadFormat::Binary)),
+        )
+        .route("/basic", get(basic::index))
+        .route("/basic/start", get(basic::start))
+        .route("/{id}/basic", get(basic::download))
         .route(
             "/upload",
-            post(upload).layer(DefaultBodyLimit::max(max_upload_size)),
+            post(upload).layer(DefaultBodyLimit::max(max_body_size)),
         )
+        .route("/{id}/upload.bin", put(upload_raw).post(upload_raw))
         .route("/results", get(results))
-        .with_state(AppState {
-            conn: Arc::default(),
-            max_upload_size: bytes_to_string(max_upload_size),
+        .route("/api/v1/sessions/{id}", get(api::session))
+        .route("/api/v1/results/{id}", get(api::result))
+        .route("/api/v1/openapi.json", get(api::openapi))
+        .nest("/backend", librespeed::router());
+    if let Some(auth_config) = config.auth {
+        app = app
+            .nest("/admin", admin::router())
+            .layer(AuthLayer::new(Authenticator::new(auth_config)?));
+    }
+    let app = app.with_state(state.clone());
+
+    for listener in &listeners {
+        info!(address = listener.url(), "Starting server...");
+    }
+    let (accepted_tx, mut accepted_rx) = mpsc::channel(128);
+    let mut accept_tasks = JoinSet::new();
+    for listener in listeners {
+        let accepted_tx = accepted_tx.clone();
+        accept_tasks.spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok(accepted) => {
+                        if accepted_tx.send(accepted).await.is_err() {
+                            break;
+                        }
+                    }
+                    // The client gave up before the connection was accepted.
+                    Err(error)
+                        if matches!(
+                            error.kind(),
+                            io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+                        ) =>
+                    {
+                        debug!(address = listener.url(), %error, "Connection aborted.")
+                    }
+                    // Running out of file descriptors or memory does not resolve
+                    // itself immediately, so back off instead of spinning.
+                    Err(error) => {
+                        warn!(address = listener.url(), %error, "Failed to accept connection.");
+                        sleep(ACCEPT_ERROR_BACKOFF).await;
+                    }
+                }
+            }
         });
+    }
+    drop(accepted_tx);
+    let mut watchdog = systemd::ready();
+
+    let graceful = GracefulShutdown::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            Some(accepted) = accepted_rx.recv() => accepted,
+            _ = watchdog.ping() => continue,
+            result = &mut shutdown => {
+                result?;
+                break;
+            }
+        };
+        tokio::spawn(listener::serve(
+            accepted,
+            app.clone(),
+            graceful.watcher(),
+            Arc::clone(&limits),
+        ));
+    }
 
-    let listener = tokio::net::TcpListener::bind((Ipv6Addr::UNSPECIFIED, server_port))
+    systemd::stopping();
+    accept_tasks.shutdown().await;
+    info!(timeout = shutdown_timeout, "Shutting down...");
+    let drain = async {
+        state.drain(Bytes::from(RestartingTemplate {}.render().unwrap()));
+        graceful.shutdown().await
+    };
+    if tokio::time::timeout(Duration::from_secs(shutdown_timeout), drain)
         .await
-        .wrap_err_with(|| format!("failed to listen on port {server_port}"))?;
-    info!(
-        address = format!("http://[::]:{server_port}"),
-        "Starting server..."
-    );
-    while let Ok((tcp_stream, addr)) = listener.accept().await {
-        let service = app.clone().layer(Extension(ConnectInfo(addr)));
-        tokio::spawn(async move {
-            let _ = hyper::server::conn::http1::Builder::new()
-                .serve_connection(TokioIo::new(tcp_stream), TowerToHyperService::new(service))
-                .await;
-        });
+        .is_err()
+    {
+        warn!("Timed out waiting for connections to close.");
+    }
+    if let Some(telemetry) = telemetry {
+        tokio::task::spawn_blocking(move || telemetry.shutdown())
+            .await
+            .wrap_err("failed to shut down telemetry")?;
     }
     Ok(())
 }
+
+async fn shutdown_signal() -> color_eyre::Result<()> {
+    let mut terminate =
+        signal(SignalKind::terminate()).wrap_err_with(|| "failed to install SIGTERM handler")?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.wrap_err_with(|| "failed to listen for SIGINT"),
+        _ = terminate.recv() => Ok(()),
+    }
+}
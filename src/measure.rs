@@ -0,0 +1,134 @@
+use std::time::{Duration, Instant};
+
+use crate::utils::calculate_bps;
+
+pub(crate) static WARMUP_DURATION: Duration = Duration::from_secs(1);
+pub(crate) static WARMUP_MAX_FRACTION: f64 = 0.25;
+
+pub(crate) struct ChunkTimings {
+    start: Instant,
+    samples: Vec<(Duration, usize)>,
+    total: usize,
+}
+
+impl ChunkTimings {
+    pub(crate) fn new(start: Instant) -> Self {
+        ChunkTimings {
+            start,
+            samples: vec![],
+            total: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, size: usize) {
+        self.record_at(self.start.elapsed(), size);
+    }
+
+    fn record_at(&mut self, elapsed: Duration, size: usize) {
+        self.total += size;
+        self.samples.push((elapsed, self.total));
+    }
+
+    pub(crate) fn total(&self) -> usize {
+        self.total
+    }
+
+    pub(crate) fn overall_bps(&self) -> Option<f64> {
+        let &(elapsed, total) = self.samples.last()?;
+        (!elapsed.is_zero()).then(|| calculate_bps(elapsed, total))
+    }
+
+    /// Duration and size of the transfer after discarding the warm-up window,
+    /// which is the smaller of [`WARMUP_DURATION`] and [`WARMUP_MAX_FRACTION`]
+    /// of the whole transfer. Falls back to the whole transfer if nothing is left.
+    pub(crate) fn steady_state(&self) -> Option<(Duration, usize)> {
+        let &(elapsed, total) = self.samples.last()?;
+        let warmup = WARMUP_DURATION.min(elapsed.mul_f64(WARMUP_MAX_FRACTION));
+        let (warmup_elapsed, warmup_total) = self
+            .samples
+            .iter()
+            .rev()
+            .find(|(sample_elapsed, _)| *sample_elapsed <= warmup)
+            .copied()
+            .unwrap_or_default();
+        let duration = elapsed - warmup_elapsed;
+        if duration.is_zero() || total == warmup_total {
+            (!elapsed.is_zero()).then_some((elapsed, total))
+        } else {
+            Some((duration, total - warmup_total))
+        }
+    }
+
+    pub(crate) fn steady_state_bps(&self) -> Option<f64> {
+        self.steady_state()
+            .map(|(duration, size)| calculate_bps(duration, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Elapsed milliseconds and size.
+    type Sample = (u64, usize);
+
+    fn timings(samples: &[Sample]) -> ChunkTimings {
+        let mut timings = ChunkTimings::new(Instant::now());
+        for &(millis, size) in samples {
+            timings.record_at(Duration::from_millis(millis), size);
+        }
+        timings
+    }
+
+    #[test]
+    fn steady_state() {
+        let cases: &[(&str, &[Sample], Option<Sample>)] = &[
+            ("no samples", &[], None),
+            ("single instant sample", &[(0, 100)], None),
+            ("single sample", &[(1_000, 100)], Some((1_000, 100))),
+            (
+                "short transfer discards a quarter",
+                &[(500, 100), (1_000, 100), (2_000, 200)],
+                Some((1_500, 300)),
+            ),
+            (
+                "long transfer discards one second",
+                &[(500, 100), (1_000, 100), (5_000, 400), (10_000, 500)],
+                Some((9_000, 900)),
+            ),
+            (
+                "sample on the window edge is warm-up",
+                &[(1_000, 100), (8_000, 700)],
+                Some((7_000, 700)),
+            ),
+            (
+                "no sample inside the window",
+                &[(2_000, 200), (8_000, 600)],
+                Some((8_000, 800)),
+            ),
+            (
+                "nothing after the window",
+                &[(100, 1_000), (4_000, 0)],
+                Some((4_000, 1_000)),
+            ),
+        ];
+        for &(name, samples, expected) in cases {
+            assert_eq!(
+                timings(samples).steady_state(),
+                expected.map(|(millis, size)| (Duration::from_millis(millis), size)),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn overall_bps() {
+        assert_eq!(timings(&[]).overall_bps(), None);
+        assert_eq!(timings(&[(0, 100)]).overall_bps(), None);
+        assert_eq!(
+            timings(&[(500, 500), (2_000, 1_500)]).overall_bps(),
+            Some(8_000.0)
+        );
+        assert_eq!(timings(&[(500, 500), (2_000, 1_500)]).total(), 2_000);
+    }
+}
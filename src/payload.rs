@@ -0,0 +1,457 @@
+use std::sync::OnceLock;
+
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+use bytes::{BufMut, Bytes, BytesMut};
+use rand::{Rng, RngCore, SeedableRng, rngs::SmallRng};
+use serde::Deserialize;
+
+pub(crate) static RANDOM_POOL: OnceLock<Bytes> = OnceLock::new();
+/// Base64 encoding of the random pool, for clients that need printable text.
+static TEXT_POOL: OnceLock<Bytes> = OnceLock::new();
+
+pub(crate) static PAYLOAD_CHUNK_SIZE: usize = 64 * 1024;
+static BITMAP_HEADER_SIZE: usize = 54;
+static BITMAP_WIDTH: usize = 4_096;
+static BITMAP_ROW_SIZE: usize = BITMAP_WIDTH * 4;
+static BITMAP_MAX_SIZE: usize = u32::MAX as usize;
+static PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+static PNG_WIDTH: usize = 4_096;
+static PNG_ROW_SIZE: usize = PNG_WIDTH * 4;
+/// Rows in every stored deflate block, which can hold at most 65535 bytes.
+static PNG_BLOCK_ROWS: usize = 3;
+static PNG_BLOCK_DATA_SIZE: usize = PNG_BLOCK_ROWS * (1 + PNG_ROW_SIZE);
+static PNG_BLOCK_SIZE: usize = 12 + 5 + PNG_BLOCK_DATA_SIZE;
+/// Signature, IHDR and an IDAT holding the zlib header.
+static PNG_HEADER_SIZE: usize = 8 + 25 + 14;
+/// IDAT holding the Adler-32 checksum and IEND.
+static PNG_TRAILER_SIZE: usize = 16 + 12;
+
+pub(crate) fn init(pool_size: usize) {
+    let mut random_data = vec![0u8; pool_size];
+    rand::rng().fill_bytes(&mut random_data);
+    TEXT_POOL
+        .set(Bytes::from(BASE64_STANDARD_NO_PAD.encode(&random_data)))
+        .unwrap();
+    RANDOM_POOL.set(Bytes::from(random_data)).unwrap();
+}
+
+/// Initializes a small pool once for all tests.
+#[cfg(test)]
+pub(crate) fn init_for_tests() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| init(1024 * 1024));
+}
+
+/// Incompressible bytes served as slices of the random pool at random offsets,
+/// so that any size can be streamed without allocating it.
+pub(crate) struct PayloadStream {
+    pool: &'static Bytes,
+    rng: SmallRng,
+    remaining: usize,
+}
+
+impl PayloadStream {
+    pub(crate) fn new(size: usize) -> Self {
+        PayloadStream {
+            pool: RANDOM_POOL.get().unwrap(),
+            rng: SmallRng::from_rng(&mut rand::rng()),
+            remaining: size,
+        }
+    }
+
+    /// Base64 characters of the random pool, which carry six random bits each
+    /// and cannot be compressed beyond that.
+    pub(crate) fn text(size: usize) -> Self {
+        PayloadStream {
+            pool: TEXT_POOL.get().unwrap(),
+            rng: SmallRng::from_rng(&mut rand::rng()),
+            remaining: size,
+        }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    pub(crate) fn next_chunk(&mut self, max_size: usize) -> Option<Bytes> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let pool = self.pool;
+        let size = self.remaining.min(max_size).min(pool.len());
+        let offset = self.rng.random_range(0..=pool.len() - size);
+        self.remaining -= size;
+        Some(pool.slice(offset..offset + size))
+    }
+}
+
+/// File format wrapping the random payload of download requests.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+pub(crate) enum PayloadFormat {
+    /// Uncompressed 32-bit BMP.
+    #[serde(rename = "bmp")]
+    Bitmap,
+    /// RGBA PNG made of stored (uncompressed) deflate blocks.
+    #[serde(rename = "png")]
+    Png,
+    /// Raw bytes without any framing.
+    #[serde(rename = "bin")]
+    Binary,
+}
+
+impl PayloadFormat {
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            PayloadFormat::Bitmap => "bmp",
+            PayloadFormat::Png => "png",
+            PayloadFormat::Binary => "bin",
+        }
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            PayloadFormat::Bitmap => "image/bmp",
+            PayloadFormat::Png => "image/png",
+            PayloadFormat::Binary => "application/octet-stream",
+        }
+    }
+
+    /// Closest valid file size to the requested one.
+    pub(crate) fn file_size(self, size: usize) -> usize {
+        match self {
+            PayloadFormat::Bitmap => {
+                let rows = (size.min(BITMAP_MAX_SIZE).saturating_sub(BITMAP_HEADER_SIZE)
+                    / BITMAP_ROW_SIZE)
+                    .max(1);
+                BITMAP_HEADER_SIZE + rows * BITMAP_ROW_SIZE
+            }
+            PayloadFormat::Png => {
+                let blocks = (size.saturating_sub(PNG_HEADER_SIZE + PNG_TRAILER_SIZE)
+                    / PNG_BLOCK_SIZE)
+                    .max(1);
+                PNG_HEADER_SIZE + blocks * PNG_BLOCK_SIZE + PNG_TRAILER_SIZE
+            }
+            PayloadFormat::Binary => size,
+        }
+    }
+}
+
+enum WriterState {
+    Header,
+    Body,
+    Trailer,
+    Done,
+}
+
+/// Streams a payload file of the given format, generating its framing around
+/// the random bytes on the fly.
+pub(crate) struct PayloadWriter {
+    format: PayloadFormat,
+    size: usize,
+    written: usize,
+    payload: PayloadStream,
+    checksum: adler2::Adler32,
+    state: WriterState,
+}
+
+impl PayloadWriter {
+    pub(crate) fn new(format: PayloadFormat, size: usize) -> Self {
+        let size = format.file_size(size);
+        let payload_size = match format {
+            PayloadFormat::Bitmap => size - BITMAP_HEADER_SIZE,
+            PayloadFormat::Png => {
+                (size - PNG_HEADER_SIZE - PNG_TRAILER_SIZE) / PNG_BLOCK_SIZE
+                    * PNG_BLOCK_ROWS
+                    * PNG_ROW_SIZE
+            }
+            PayloadFormat::Binary => size,
+        };
+        PayloadWriter {
+            format,
+            size,
+            written: 0,
+            payload: PayloadStream::new(payload_size),
+            checksum: adler2::Adler32::new(),
+            state: WriterState::Header,
+        }
+    }
+
+    /// Unframed base64 text, for payloads embedded in HTML.
+    pub(crate) fn text(size: usize) -> Self {
+        PayloadWriter {
+            payload: PayloadStream::text(size),
+            ..PayloadWriter::new(PayloadFormat::Binary, size)
+        }
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.size - self.written
+    }
+
+    pub(crate) fn next_frame(&mut self) -> Option<Bytes> {
+        let frame = match self.state {
+            WriterState::Header => {
+                self.state = WriterState::Body;
+                match self.format {
+                    PayloadFormat::Bitmap => bitmap_header(self.size),
+                    PayloadFormat::Png => png_header(self.size),
+                    PayloadFormat::Binary => return self.next_frame(),
+                }
+            }
+            WriterState::Body => {
+                let chunk = match self.format {
+                    PayloadFormat::Bitmap | PayloadFormat::Binary => {
+                        self.payload.next_chunk(PAYLOAD_CHUNK_SIZE)
+                    }
+                    PayloadFormat::Png => self.png_block(),
+                };
+                match chunk {
+                    Some(chunk) => chunk,
+                    None => {
+                        self.state = WriterState::Trailer;
+                        return self.next_frame();
+                    }
+                }
+            }
+            WriterState::Trailer => {
+                self.state = WriterState::Done;
+                match self.format {
+                    PayloadFormat::Png => {
+                        let mut trailer = BytesMut::with_capacity(PNG_TRAILER_SIZE);
+                        put_png_chunk(
+                            &mut trailer,
+                            b"IDAT",
+                            &self.checksum.checksum().to_be_bytes(),
+                        );
+                        put_png_chunk(&mut trailer, b"IEND", &[]);
+                        trailer.freeze()
+                    }
+                    PayloadFormat::Bitmap | PayloadFormat::Binary => return None,
+                }
+            }
+            WriterState::Done => return None,
+        };
+        self.written += frame.len();
+        Some(frame)
+    }
+
+    /// IDAT chunk holding a single stored deflate block of unfiltered rows.
+    fn png_block(&mut self) -> Option<Bytes> {
+        if self.payload.remaining() == 0 {
+            return None;
+        }
+        let mut data = BytesMut::with_capacity(5 + PNG_BLOCK_DATA_SIZE);
+        let last = self.payload.remaining() == PNG_BLOCK_ROWS * PNG_ROW_SIZE;
+        data.put_u8(last.into());
+        data.put_u16_le(PNG_BLOCK_DATA_SIZE as u16);
+        data.put_u16_le(!(PNG_BLOCK_DATA_SIZE as u16));
+        for _ in 0..PNG_BLOCK_ROWS {
+            let row_start = data.len();
+            data.put_u8(0);
+            data.put_slice(&self.payload.next_chunk(PNG_ROW_SIZE)?);
+            self.checksum.write_slice(&data[row_start..]);
+        }
+        let mut block = BytesMut::with_capacity(PNG_BLOCK_SIZE);
+        put_png_chunk(&mut block, b"IDAT", &data);
+        Some(block.freeze())
+    }
+}
+
+fn bitmap_header(size: usize) -> Bytes {
+    let image_size = size - BITMAP_HEADER_SIZE;
+    let mut header = BytesMut::with_capacity(BITMAP_HEADER_SIZE);
+    header.put_slice(b"BM");
+    header.put_u32_le(size as u32);
+    header.put_u32_le(0);
+    header.put_u32_le(BITMAP_HEADER_SIZE as u32);
+    header.put_u32_le(40);
+    header.put_i32_le(BITMAP_WIDTH as i32);
+    header.put_i32_le((image_size / BITMAP_ROW_SIZE) as i32);
+    header.put_u16_le(1);
+    header.put_u16_le(32);
+    header.put_u32_le(0);
+    header.put_u32_le(image_size as u32);
+    header.put_i32_le(2_835);
+    header.put_i32_le(2_835);
+    header.put_u32_le(0);
+    header.put_u32_le(0);
+    header.freeze()
+}
+
+fn png_header(size: usize) -> Bytes {
+    let blocks = (size - PNG_HEADER_SIZE - PNG_TRAILER_SIZE) / PNG_BLOCK_SIZE;
+    let mut ihdr = BytesMut::with_capacity(13);
+    ihdr.put_u32(PNG_WIDTH as u32);
+    ihdr.put_u32((blocks * PNG_BLOCK_ROWS) as u32);
+    // 8-bit RGBA, deflate, no filtering method extensions, no interlacing.
+    ihdr.put_slice(&[8, 6, 0, 0, 0]);
+    let mut header = BytesMut::with_capacity(PNG_HEADER_SIZE);
+    header.put_slice(PNG_SIGNATURE);
+    put_png_chunk(&mut header, b"IHDR", &ihdr);
+    // zlib header for a deflate stream without compression.
+    put_png_chunk(&mut header, b"IDAT", &[0x78, 0x01]);
+    header.freeze()
+}
+
+fn put_png_chunk(buf: &mut BytesMut, chunk_type: &[u8; 4], data: &[u8]) {
+    buf.put_u32(data.len() as u32);
+    let crc_start = buf.len();
+    buf.put_slice(chunk_type);
+    buf.put_slice(data);
+    let crc = crc32fast::hash(&buf[crc_start..]);
+    buf.put_u32(crc);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(format: PayloadFormat, size: usize) -> Vec<u8> {
+        init_for_tests();
+        collect(PayloadWriter::new(format, size))
+    }
+
+    fn collect(mut writer: PayloadWriter) -> Vec<u8> {
+        let mut file = Vec::new();
+        while let Some(frame) = writer.next_frame() {
+            file.extend_from_slice(&frame);
+        }
+        assert_eq!(writer.remaining(), 0);
+        assert_eq!(file.len(), writer.size());
+        file
+    }
+
+    /// Type and data of every chunk, checking their CRCs.
+    fn png_chunks(file: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        assert_eq!(&file[..8], PNG_SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &file[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32fast::hash(&rest[4..8 + len]));
+            chunks.push((rest[4..8].try_into().unwrap(), &rest[8..8 + len]));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn file_sizes() {
+        let png_block_sizes = PNG_HEADER_SIZE + PNG_TRAILER_SIZE;
+        let cases = [
+            (PayloadFormat::Binary, 0, 0),
+            (PayloadFormat::Binary, 100_000, 100_000),
+            (
+                PayloadFormat::Bitmap,
+                0,
+                BITMAP_HEADER_SIZE + BITMAP_ROW_SIZE,
+            ),
+            (
+                PayloadFormat::Bitmap,
+                BITMAP_HEADER_SIZE + 3 * BITMAP_ROW_SIZE - 1,
+                BITMAP_HEADER_SIZE + 2 * BITMAP_ROW_SIZE,
+            ),
+            (PayloadFormat::Png, 0, png_block_sizes + PNG_BLOCK_SIZE),
+            (
+                PayloadFormat::Png,
+                png_block_sizes + 2 * PNG_BLOCK_SIZE,
+                png_block_sizes + 2 * PNG_BLOCK_SIZE,
+            ),
+            (
+                PayloadFormat::Png,
+                png_block_sizes + 3 * PNG_BLOCK_SIZE - 1,
+                png_block_sizes + 2 * PNG_BLOCK_SIZE,
+            ),
+        ];
+        for (format, size, expected) in cases {
+            let name = format.extension();
+            assert_eq!(format.file_size(size), expected, "{name} {size}");
+            assert_eq!(write(format, size).len(), expected, "{name} {size}");
+        }
+    }
+
+    #[test]
+    fn bitmap_header() {
+        let file = write(PayloadFormat::Bitmap, 1_000_000);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap());
+        assert_eq!(&file[..2], b"BM");
+        assert_eq!(u32_at(2) as usize, file.len());
+        assert_eq!(u32_at(10) as usize, BITMAP_HEADER_SIZE);
+        assert_eq!(u32_at(18) as usize, BITMAP_WIDTH);
+        assert_eq!(
+            u32_at(22) as usize,
+            (file.len() - BITMAP_HEADER_SIZE) / BITMAP_ROW_SIZE
+        );
+        assert_eq!(u32_at(34) as usize, file.len() - BITMAP_HEADER_SIZE);
+    }
+
+    #[test]
+    fn png_framing() {
+        for blocks in [1, 2, 5] {
+            let file = write(
+                PayloadFormat::Png,
+                PNG_HEADER_SIZE + blocks * PNG_BLOCK_SIZE + PNG_TRAILER_SIZE,
+            );
+            let chunks = png_chunks(&file);
+            assert_eq!(chunks.len(), blocks + 4, "{blocks} blocks");
+            let (ihdr, rest) = chunks.split_first().unwrap();
+            let (iend, idats) = rest.split_last().unwrap();
+            assert_eq!(&ihdr.0, b"IHDR");
+            assert_eq!(&ihdr.1[..4], &(PNG_WIDTH as u32).to_be_bytes());
+            assert_eq!(
+                &ihdr.1[4..8],
+                &((blocks * PNG_BLOCK_ROWS) as u32).to_be_bytes()
+            );
+            assert_eq!(iend, &(*b"IEND", &[][..]));
+
+            let zlib: Vec<u8> = idats
+                .iter()
+                .flat_map(|(chunk_type, data)| {
+                    assert_eq!(chunk_type, b"IDAT");
+                    data.iter().copied()
+                })
+                .collect();
+            assert_eq!(&zlib[..2], &[0x78, 0x01]);
+            let mut deflate = &zlib[2..zlib.len() - 4];
+            let mut image = Vec::new();
+            for block in 0..blocks {
+                let last = block == blocks - 1;
+                assert_eq!(deflate[0], u8::from(last), "block {block} of {blocks}");
+                let len = u16::from_le_bytes([deflate[1], deflate[2]]);
+                let nlen = u16::from_le_bytes([deflate[3], deflate[4]]);
+                assert_eq!(len as usize, PNG_BLOCK_DATA_SIZE);
+                assert_eq!(nlen, !len);
+                image.extend_from_slice(&deflate[5..5 + len as usize]);
+                deflate = &deflate[5 + len as usize..];
+            }
+            assert!(deflate.is_empty());
+            assert_eq!(image.len(), blocks * PNG_BLOCK_ROWS * (1 + PNG_ROW_SIZE));
+            assert!(
+                image
+                    .chunks(1 + PNG_ROW_SIZE)
+                    .all(|row| row[0] == 0 && row.len() == 1 + PNG_ROW_SIZE)
+            );
+            assert_eq!(
+                &zlib[zlib.len() - 4..],
+                &adler2::adler32_slice(&image).to_be_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn text_payload() {
+        init_for_tests();
+        let text = collect(PayloadWriter::text(200_000));
+        assert_eq!(text.len(), 200_000);
+        assert!(
+            text.iter()
+                .all(|byte| byte.is_ascii_alphanumeric() || *byte == b'+' || *byte == b'/')
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use hmac::{Hmac, KeyInit, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::config::{IpAnonymization, PrivacyConfig};
+
+static POLICY: OnceLock<PrivacyPolicy> = OnceLock::new();
+
+/// Bytes of the keyed hash kept in anonymized addresses.
+static HASH_SIZE: usize = 8;
+
+enum Anonymizer {
+    None,
+    Truncate,
+    Hash(Hmac<Sha256>),
+}
+
+pub(crate) struct PrivacyPolicy {
+    anonymizer: Anonymizer,
+    pub(crate) results_retention: Duration,
+    pub(crate) ip_retention: Duration,
+}
+
+pub(crate) fn init(config: &PrivacyConfig) {
+    let results_retention = Duration::from_secs(config.results_retention);
+    let _ = POLICY.set(PrivacyPolicy {
+        anonymizer: Anonymizer::new(config),
+        results_retention,
+        ip_retention: config
+            .ip_retention
+            .map_or(results_retention, Duration::from_secs),
+    });
+}
+
+pub(crate) fn policy() -> &'static PrivacyPolicy {
+    POLICY.get().expect("privacy policy is not initialized")
+}
+
+/// Client address as it may appear in logs and stored results.
+pub(crate) fn anonymize(ip: IpAddr) -> String {
+    policy().anonymizer.anonymize(ip)
+}
+
+impl Anonymizer {
+    fn new(config: &PrivacyConfig) -> Self {
+        match config.ip_anonymization {
+            IpAnonymization::None => Anonymizer::None,
+            IpAnonymization::Truncate => Anonymizer::Truncate,
+            IpAnonymization::Hash => {
+                // Without a configured key, hashes only correlate within a single run.
+                let key = match &config.ip_hash_key {
+                    Some(key) => key.as_bytes().to_vec(),
+                    None => {
+                        let mut key = vec![0u8; 32];
+                        rand::rng().fill_bytes(&mut key);
+                        key
+                    }
+                };
+                Anonymizer::Hash(Hmac::new_from_slice(&key).unwrap())
+            }
+        }
+    }
+
+    fn anonymize(&self, ip: IpAddr) -> String {
+        let ip = ip.to_canonical();
+        match self {
+            Anonymizer::None => ip.to_string(),
+            Anonymizer::Truncate => match ip {
+                IpAddr::V4(ip) => {
+                    let [a, b, c, _] = ip.octets();
+                    format!("{}/24", Ipv4Addr::new(a, b, c, 0))
+                }
+                IpAddr::V6(ip) => {
+                    let [a, b, c, ..] = ip.segments();
+                    format!("{}/48", Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+                }
+            },
+            Anonymizer::Hash(mac) => {
+                let mut mac = mac.clone();
+                match ip {
+                    IpAddr::V4(ip) => mac.update(&ip.octets()),
+                    IpAddr::V6(ip) => mac.update(&ip.octets()),
+                }
+                mac.finalize().into_bytes()[..HASH_SIZE].iter().fold(
+                    String::with_capacity(HASH_SIZE * 2),
+                    |mut hash, byte| {
+                        let _ = write!(hash, "{byte:02x}");
+                        hash
+                    },
+                )
+            }
+        }
+    }
+}
+
+impl PrivacyPolicy {
+    pub(crate) fn anonymization(&self) -> &'static str {
+        match self.anonymizer {
+            Anonymizer::None => "none",
+            Anonymizer::Truncate => "truncate",
+            Anonymizer::Hash(_) => "hash",
+        }
+    }
+}
+
+/// Duration in the largest unit that divides it, such as "90 minutes" or "1 day".
+pub(crate) fn duration_to_string(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (value, unit) = [(86_400, "day"), (3_600, "hour"), (60, "minute")]
+        .into_iter()
+        .find(|(unit_seconds, _)| seconds >= *unit_seconds && seconds.is_multiple_of(*unit_seconds))
+        .map_or((seconds, "second"), |(unit_seconds, unit)| {
+            (seconds / unit_seconds, unit)
+        });
+    if value == 1 {
+        format!("{value} {unit}")
+    } else {
+        format!("{value} {unit}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anonymizer(ip_anonymization: IpAnonymization, ip_hash_key: Option<&str>) -> Anonymizer {
+        Anonymizer::new(&PrivacyConfig {
+            ip_anonymization,
+            ip_hash_key: ip_hash_key.map(Into::into),
+            ..PrivacyConfig::default()
+        })
+    }
+
+    #[test]
+    fn truncate() {
+        let cases = [
+            ("192.0.2.77", "192.0.2.0/24"),
+            ("192.0.2.0", "192.0.2.0/24"),
+            ("255.255.255.255", "255.255.255.0/24"),
+            ("::ffff:192.0.2.77", "192.0.2.0/24"),
+            ("2001:db8:1:2:3:4:5:6", "2001:db8:1::/48"),
+            ("2001:db8:1::", "2001:db8:1::/48"),
+            ("::1", "::/48"),
+        ];
+        let anonymizer = anonymizer(IpAnonymization::Truncate, None);
+        for (ip, expected) in cases {
+            assert_eq!(anonymizer.anonymize(ip.parse().unwrap()), expected, "{ip}");
+        }
+    }
+
+    #[test]
+    fn none() {
+        let anonymizer = anonymizer(IpAnonymization::None, None);
+        for (ip, expected) in [
+            ("192.0.2.77", "192.0.2.77"),
+            ("::ffff:192.0.2.77", "192.0.2.77"),
+            ("2001:db8::1", "2001:db8::1"),
+        ] {
+            assert_eq!(anonymizer.anonymize(ip.parse().unwrap()), expected, "{ip}");
+        }
+    }
+
+    #[test]
+    fn hash() {
+        let keyed = anonymizer(IpAnonymization::Hash, Some("key"));
+        let hash = |anonymizer: &Anonymizer, ip: &str| anonymizer.anonymize(ip.parse().unwrap());
+        // Truncated HMAC-SHA256 of the address bytes, which must not change
+        // across versions for configured keys.
+        for (ip, hashed) in [
+            ("192.0.2.77", "9c899f1b4f24bb8f"),
+            ("2001:db8::1", "fe73478b327c09e3"),
+        ] {
+            assert_eq!(hash(&keyed, ip), hashed, "{ip}");
+            assert_eq!(
+                hash(&anonymizer(IpAnonymization::Hash, Some("key")), ip),
+                hashed,
+                "{ip} with the same key"
+            );
+            assert_ne!(
+                hash(&anonymizer(IpAnonymization::Hash, Some("other")), ip),
+                hashed,
+                "{ip} with another key"
+            );
+            assert_ne!(
+                hash(&anonymizer(IpAnonymization::Hash, None), ip),
+                hashed,
+                "{ip} with a random key"
+            );
+        }
+        assert_eq!(
+            hash(&keyed, "::ffff:192.0.2.77"),
+            hash(&keyed, "192.0.2.77")
+        );
+        assert_ne!(hash(&keyed, "192.0.2.77"), hash(&keyed, "192.0.2.78"));
+    }
+}
@@ -0,0 +1,229 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use color_eyre::eyre::{Context, eyre};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+static V1_PREFIX: &[u8] = b"PROXY ";
+static V1_MAX_LENGTH: usize = 107;
+static V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_COMMAND_PROXY: u8 = 0x21;
+const V2_COMMAND_LOCAL: u8 = 0x20;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// Reads a PROXY protocol header of either version, returning the original
+/// source address unless the proxy sent it on its own behalf.
+///
+/// Reads exactly the header, so the stream can be handed to hyper afterwards.
+pub(crate) async fn read_header<I: AsyncRead + Unpin>(
+    io: &mut I,
+) -> color_eyre::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 8];
+    io.read_exact(&mut prefix)
+        .await
+        .wrap_err("failed to read PROXY header")?;
+    if prefix.starts_with(V1_PREFIX) {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(eyre!("PROXY v1 header too long"));
+            }
+            line.push(io.read_u8().await.wrap_err("failed to read PROXY header")?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else if V2_SIGNATURE.starts_with(&prefix) {
+        let mut rest = [0u8; 8];
+        io.read_exact(&mut rest)
+            .await
+            .wrap_err("failed to read PROXY header")?;
+        if rest[..4] != V2_SIGNATURE[8..] {
+            return Err(eyre!("invalid PROXY v2 signature"));
+        }
+        let mut addresses = vec![0u8; u16::from_be_bytes([rest[6], rest[7]]) as usize];
+        io.read_exact(&mut addresses)
+            .await
+            .wrap_err("failed to read PROXY header")?;
+        parse_v2(rest[4], rest[5], &addresses)
+    } else {
+        Err(eyre!("missing PROXY header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> color_eyre::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).wrap_err("invalid PROXY v1 header")?;
+    let mut fields = line.split(' ').skip(1);
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {
+            let (Some(source), Some(_), Some(port), Some(_), None) = (
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+            ) else {
+                return Err(eyre!("invalid PROXY v1 header {line:?}"));
+            };
+            let ip: IpAddr = source.parse().wrap_err("invalid PROXY v1 address")?;
+            let port: u16 = port.parse().wrap_err("invalid PROXY v1 port")?;
+            Ok(Some((ip, port).into()))
+        }
+        Some("UNKNOWN") => Ok(None),
+        _ => Err(eyre!("invalid PROXY v1 header {line:?}")),
+    }
+}
+
+fn parse_v2(command: u8, family: u8, addresses: &[u8]) -> color_eyre::Result<Option<SocketAddr>> {
+    if command == V2_COMMAND_LOCAL {
+        return Ok(None);
+    }
+    if command != V2_COMMAND_PROXY {
+        return Err(eyre!("unsupported PROXY v2 command {command:#x}"));
+    }
+    match family {
+        V2_TCP4 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some((ip, port).into()))
+        }
+        V2_TCP6 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some((ip, port).into()))
+        }
+        // UDP, Unix sockets and unspecified families carry no usable client address.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn tcp4() -> Vec<u8> {
+        [[192, 0, 2, 1], [198, 51, 100, 2]]
+            .concat()
+            .into_iter()
+            .chain(1234u16.to_be_bytes())
+            .chain(443u16.to_be_bytes())
+            .collect()
+    }
+
+    fn tcp6() -> Vec<u8> {
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        addresses.extend_from_slice(&1234u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        addresses
+    }
+
+    #[tokio::test]
+    async fn valid_headers() {
+        let cases: Vec<(&str, Vec<u8>, Option<&str>)> = vec![
+            (
+                "v1 TCP4",
+                b"PROXY TCP4 192.0.2.1 198.51.100.2 1234 443\r\n".to_vec(),
+                Some("192.0.2.1:1234"),
+            ),
+            (
+                "v1 TCP6",
+                b"PROXY TCP6 2001:db8::1 ::1 1234 443\r\n".to_vec(),
+                Some("[2001:db8::1]:1234"),
+            ),
+            ("v1 UNKNOWN", b"PROXY UNKNOWN\r\n".to_vec(), None),
+            (
+                "v1 UNKNOWN with addresses",
+                b"PROXY UNKNOWN ::1 ::1 1 2\r\n".to_vec(),
+                None,
+            ),
+            (
+                "v2 TCP4",
+                v2(V2_COMMAND_PROXY, V2_TCP4, &tcp4()),
+                Some("192.0.2.1:1234"),
+            ),
+            (
+                "v2 TCP6",
+                v2(V2_COMMAND_PROXY, V2_TCP6, &tcp6()),
+                Some("[2001:db8::1]:1234"),
+            ),
+            (
+                "v2 TCP4 with TLVs",
+                v2(
+                    V2_COMMAND_PROXY,
+                    V2_TCP4,
+                    &[tcp4(), vec![0x04, 0, 1, 0]].concat(),
+                ),
+                Some("192.0.2.1:1234"),
+            ),
+            ("v2 LOCAL", v2(V2_COMMAND_LOCAL, 0, &[]), None),
+            ("v2 UDP", v2(V2_COMMAND_PROXY, 0x12, &tcp4()), None),
+            ("v2 unspecified", v2(V2_COMMAND_PROXY, 0, &[]), None),
+        ];
+        for (name, header, expected) in cases {
+            let request = b"GET / HTTP/1.1\r\n";
+            let stream = [header.as_slice(), request].concat();
+            let mut io = stream.as_slice();
+            let source = read_header(&mut io).await.unwrap();
+            assert_eq!(source, expected.map(|addr| addr.parse().unwrap()), "{name}");
+            assert_eq!(io, request, "{name} must leave the request unread");
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_headers() {
+        let cases: Vec<(&str, Vec<u8>)> = vec![
+            ("empty", vec![]),
+            ("HTTP request", b"GET / HTTP/1.1\r\n\r\n".to_vec()),
+            ("v1 unterminated", b"PROXY TCP4 192.0.2.1".to_vec()),
+            (
+                "v1 too long",
+                [b"PROXY TCP4 ", &[b'1'; 120][..], b"\r\n"].concat(),
+            ),
+            (
+                "v1 missing fields",
+                b"PROXY TCP4 192.0.2.1 198.51.100.2 1234\r\n".to_vec(),
+            ),
+            (
+                "v1 extra field",
+                b"PROXY TCP4 192.0.2.1 198.51.100.2 1234 443 0\r\n".to_vec(),
+            ),
+            (
+                "v1 bad address",
+                b"PROXY TCP4 192.0.2 198.51.100.2 1234 443\r\n".to_vec(),
+            ),
+            (
+                "v1 bad port",
+                b"PROXY TCP4 192.0.2.1 198.51.100.2 70000 443\r\n".to_vec(),
+            ),
+            (
+                "v1 bad protocol",
+                b"PROXY UDP4 192.0.2.1 198.51.100.2 1234 443\r\n".to_vec(),
+            ),
+            (
+                "v1 invalid UTF-8",
+                b"PROXY TCP4 \xff 198.51.100.2 1234 443\r\n".to_vec(),
+            ),
+            (
+                "v2 bad signature",
+                [&V2_SIGNATURE[..8], b"QUIX\x21\x11\0\0"].concat(),
+            ),
+            (
+                "v2 truncated",
+                v2(V2_COMMAND_PROXY, V2_TCP4, &tcp4())[..20].to_vec(),
+            ),
+            ("v2 bad command", v2(0x22, V2_TCP4, &tcp4())),
+            ("v2 bad version", v2(0x11, V2_TCP4, &tcp4())),
+        ];
+        for (name, header) in cases {
+            let mut io = header.as_slice();
+            assert!(read_header(&mut io).await.is_err(), "{name}");
+        }
+    }
+}
@@ -1,55 +1,122 @@
 use std::{
     net::SocketAddr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use askama::Template;
 use axum::{
+    Extension,
     body::Body,
     extract::{ConnectInfo, Multipart, Path, Query, State},
-    http::{HeaderMap, StatusCode, header},
-    response::{Html, IntoResponse, Redirect},
+    http::{HeaderMap, StatusCode, Version, header},
+    response::{Html, IntoResponse, Json, Redirect},
 };
 use bytes::Bytes;
+use http_body::Frame;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
 use serde::{Deserialize, Serialize};
 use tokio::time::sleep;
-use tracing::info;
+use tracing::{Instrument, Span, info, info_span};
 use uuid::Uuid;
 
 use crate::{
-    download::{DOWNLOAD_START_SIZE, DOWNLOAD_TEST_DURATION, DownloadBody, DownloadState},
+    basic,
+    connection::{ConnectionDetails, ConnectionSocket, ConnectionWrites, TlsInfo},
+    download::{DownloadBody, DownloadFormat},
+    geoip::NetworkInfo,
+    measure::ChunkTimings,
+    payload::PayloadFormat,
+    privacy::{duration_to_string, policy},
     session::AppState,
     templates::{
-        FinishDownloadTemplate, IndexTemplate, PrivacyTemplate, ResultsTemplate,
+        CancelTemplate, FinishDownloadTemplate, IndexTemplate, PrivacyTemplate, ResultsTemplate,
         StartDownloadTemplate,
     },
-    utils::{bps_to_string, calculate_bps},
+    terminal,
+    utils::{
+        accept_quality, bps_to_string, bytes_to_string, client_ip, has_proxy_hints,
+        measurement_headers,
+    },
 };
 
+#[derive(Deserialize)]
+pub(crate) struct IndexQuery {
+    format: Option<PayloadFormat>,
+}
+
 pub(crate) async fn index(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    socket: Option<Extension<ConnectionSocket>>,
+    tls: Option<Extension<TlsInfo>>,
+    version: Version,
+    Query(IndexQuery { format }): Query<IndexQuery>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
+    if basic::is_text_browser(&headers) {
+        return Redirect::to("/basic").into_response();
+    }
+    if state.at_session_limit() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many tests are running, please try again later.\n",
+        )
+            .into_response();
+    }
     let id = Uuid::new_v4();
-    let addr = if let Some(ip) = headers.get("X-Forwarded-For")
-        && let Ok(ip_str) = ip.to_str()
-        && let Some(first_ip_str) = ip_str.split(',').next()
-        && let Ok(ip) = first_ip_str.parse()
-    {
-        ip
-    } else {
-        addr.ip().to_canonical()
+    let connection = ConnectionDetails::new(
+        &state,
+        addr,
+        &headers,
+        version,
+        tls.map(|Extension(tls)| tls),
+    );
+    let addr = client_ip(&headers, addr);
+    let terminal = terminal::is_terminal_client(&headers);
+    let (sender, body) = state.insert(id, addr, terminal);
+    let span = state.span(id);
+    span.in_scope(|| info!("New connection."));
+    if has_proxy_hints(&headers) {
+        state.flag_proxied(id);
+    }
+    if terminal {
+        let task = tokio::spawn(
+            terminal::run(
+                state.clone(),
+                id,
+                sender,
+                body.sent(),
+                socket.map(|Extension(socket)| socket),
+                terminal::upload_url(&headers, id),
+            )
+            .instrument(span),
+        );
+        state.set_task(id, task.abort_handle());
+        return (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            [(header::TRANSFER_ENCODING, "chunked")],
+            measurement_headers(),
+            Body::new(body),
+        )
+            .into_response();
+    }
+    let network = state.network(id);
+    let html = IndexTemplate {
+        id,
+        profiles: &state.profiles,
+        format: format.unwrap_or(state.payload_format).extension(),
+        provider: network.as_ref().and_then(NetworkInfo::provider),
+        location: network.as_ref().and_then(NetworkInfo::location),
+        connection,
     };
-    info!(%id, %addr, "New connection.");
-    let (sender, body) = state.insert(id, addr);
-    let html = IndexTemplate { id };
     sender.send(Bytes::from(html.render().unwrap())).await;
     (
         [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
         [(header::TRANSFER_ENCODING, "chunked")],
         Body::new(body),
     )
+        .into_response()
 }
 
 pub(crate) async fn favicon() -> impl IntoResponse {
@@ -59,95 +126,194 @@ pub(crate) async fn favicon() -> impl IntoResponse {
     )
 }
 
-pub(crate) async fn privacy() -> impl IntoResponse {
-    Html(PrivacyTemplate {}.render().unwrap())
+pub(crate) async fn privacy(State(state): State<AppState>) -> impl IntoResponse {
+    let policy = policy();
+    Html(
+        PrivacyTemplate {
+            anonymization: policy.anonymization(),
+            results_retention: duration_to_string(policy.results_retention),
+            ip_retention: (policy.ip_retention < policy.results_retention)
+                .then(|| duration_to_string(policy.ip_retention)),
+            geoip: state.geoip.is_some(),
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+#[derive(Deserialize)]
+pub(crate) struct StartQuery {
+    pub(crate) profile: Option<String>,
+    pub(crate) format: Option<PayloadFormat>,
 }
 
 pub(crate) async fn start(
     State(state): State<AppState>,
     Path(id): Path<Uuid>,
+    Query(StartQuery { profile, format }): Query<StartQuery>,
 ) -> impl IntoResponse {
-    if let Some((sender, start)) = state.start_download(id) {
-        let html = StartDownloadTemplate {
-            id,
-            test_duration: DOWNLOAD_TEST_DURATION,
-            start_size: DOWNLOAD_START_SIZE,
-            timestamp: start.elapsed().as_secs_f64(),
-        };
-        sender.send(Bytes::from(html.render().unwrap())).await;
-        tokio::spawn(async move {
-            sleep(Duration::from_secs(DOWNLOAD_TEST_DURATION)).await;
-            if let Some((download, latency)) = state.stop_download(id) {
-                let html = FinishDownloadTemplate {
-                    download,
-                    latency,
-                    max_upload_size: state.max_upload_size.clone(),
-                };
-                sender.send(Bytes::from(html.render().unwrap())).await;
-                state.finish(id).await;
-            }
-        });
+    let session_span = state.span(id);
+    let span = info_span!(parent: &session_span, "start");
+    async move {
+        if let Some(profile) = state.profile(profile.as_deref())
+            && let Some((sender, start)) = state.start_download(id, Arc::clone(&profile))
+        {
+            info!(profile = profile.name, "Starting test.");
+            let html = StartDownloadTemplate {
+                id,
+                test_duration: profile.duration,
+                start_size: profile.start_size,
+                extension: format.unwrap_or(state.payload_format).extension(),
+                streams: profile.streams,
+                timestamp: start.elapsed().as_secs_f64(),
+            };
+            sender.send(Bytes::from(html.render().unwrap())).await;
+            let task = tokio::spawn(
+                {
+                    let state = state.clone();
+                    async move {
+                        sleep(Duration::from_secs(profile.duration)).await;
+                        if let Some((download, latency)) = state.stop_download(id) {
+                            let html = FinishDownloadTemplate {
+                                id,
+                                download,
+                                latency,
+                                max_upload_size: bytes_to_string(profile.max_upload_size),
+                            };
+                            sender.send(Bytes::from(html.render().unwrap())).await;
+                            state.finish(id).await;
+                        }
+                    }
+                }
+                .instrument(session_span),
+            );
+            state.set_task(id, task.abort_handle());
+        }
+    }
+    .instrument(span)
+    .await;
+    measurement_headers()
+}
+
+pub(crate) async fn cancel(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> impl IntoResponse {
+    if let Some(sender) = state.cancel(id) {
+        state.span(id).in_scope(|| info!("Cancelled test."));
+        sender
+            .send(Bytes::from(CancelTemplate {}.render().unwrap()))
+            .await;
     }
+    measurement_headers()
 }
 
 #[derive(Deserialize)]
 pub(crate) struct DownloadQuery {
-    i: usize,
-    size: usize,
-    ts: f64,
+    pub(crate) i: usize,
+    #[serde(default)]
+    pub(crate) s: usize,
+    pub(crate) size: usize,
+    pub(crate) ts: f64,
 }
 
 pub(crate) async fn download(
     State(state): State<AppState>,
+    Extension(format): Extension<PayloadFormat>,
+    writes: Option<Extension<ConnectionWrites>>,
     Path(id): Path<Uuid>,
     Query(DownloadQuery {
         size,
+        s: stream,
         i: counter,
         ts: timestamp,
     }): Query<DownloadQuery>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    state.measure_download_latency(id, timestamp, counter);
+    if has_proxy_hints(&headers) {
+        state.flag_proxied(id);
+    }
+    state.measure_download_latency(id, timestamp, stream, counter);
     (
-        [(header::CONTENT_TYPE, "image/bmp")],
-        Body::new(DownloadBody {
-            app_state: state,
+        measurement_headers(),
+        [(header::CONTENT_TYPE, format.content_type())],
+        Body::new(DownloadBody::new(
+            state.clone(),
             id,
-            size,
+            size.min(state.max_download_bytes),
+            stream,
             counter,
-            download_state: DownloadState::Waiting,
-        }),
+            DownloadFormat::Payload(format),
+            writes.map(|Extension(writes)| writes),
+        )),
     )
 }
 
-pub(crate) async fn upload(mut multipart: Multipart) -> impl IntoResponse {
-    let start = Instant::now();
+pub(crate) async fn upload(
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    let mut id = None;
+    let mut profile = None;
     let mut download = None;
     let mut latency = None;
-    let mut file_size = None;
-    let mut duration = None;
+    let mut timings = None;
     while let Ok(Some(mut field)) = multipart.next_field().await {
         match field.name().unwrap() {
+            "id" => {
+                id = field.text().await.ok().and_then(|id| id.parse().ok());
+                profile = id
+                    .and_then(|id| state.results.get(&id))
+                    .and_then(|result| state.profile(result.profile.as_deref()));
+            }
             "download" => download = field.text().await.ok(),
             "latency" => latency = field.text().await.ok(),
             "file" => {
-                while let Ok(Some(chunk)) = field.chunk().await {
-                    file_size = file_size.or(Some(0)).map(|size| size + chunk.len());
-                    duration = Some(start.elapsed());
+                let max_upload_size = profile
+                    .as_ref()
+                    .map_or(state.max_upload_bytes, |profile| profile.max_upload_size);
+                let file_timings = timings.insert(ChunkTimings::new(Instant::now()));
+                let session_span = id.map_or_else(Span::none, |id| state.span(id));
+                let too_large = async {
+                    while let Ok(Some(chunk)) = field.chunk().await {
+                        file_timings.record(chunk.len());
+                        if file_timings.total() > max_upload_size {
+                            return true;
+                        }
+                    }
+                    false
+                }
+                .instrument(info_span!(parent: &session_span, "upload"))
+                .await;
+                if too_large {
+                    return StatusCode::PAYLOAD_TOO_LARGE.into_response();
                 }
             }
             _ => (),
         }
     }
-    if let (Some(file_size), Some(download), Some(latency), Some(duration)) =
-        (file_size, download, latency, duration)
+    if let (Some(timings), Some(download), Some(latency)) = (timings, download, latency)
+        && let Some(upload_overall) = timings.overall_bps()
+        && let Some(upload) = timings.steady_state_bps()
     {
-        let upload = bps_to_string(calculate_bps(duration, file_size));
+        if let Some(id) = id {
+            state.record_upload(id, timings.total(), upload, upload_overall);
+        }
+        let (possibly_proxied, network) = id
+            .and_then(|id| state.results.get(&id))
+            .map(|result| (result.possibly_proxied, result.network.clone()))
+            .unwrap_or_default();
         let uri = format!(
             "/results?{}",
             serde_urlencoded::to_string(ResultsQuery {
+                profile: profile.map(|profile| profile.name.clone()),
                 download,
-                upload,
-                latency
+                upload: bps_to_string(upload),
+                upload_overall: Some(bps_to_string(upload_overall)),
+                latency,
+                possibly_proxied,
+                provider: network.as_ref().and_then(NetworkInfo::provider),
+                location: network.as_ref().and_then(NetworkInfo::location),
             })
             .unwrap()
         );
@@ -157,27 +323,202 @@ pub(crate) async fn upload(mut multipart: Multipart) -> impl IntoResponse {
     }
 }
 
+#[derive(Serialize)]
+pub(crate) struct RawUploadResult {
+    id: Uuid,
+    size: usize,
+    upload_bps: f64,
+    upload_overall_bps: f64,
+    upload: String,
+    upload_overall: String,
+}
+
+pub(crate) async fn upload_raw(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    body: Body,
+) -> impl IntoResponse {
+    if let Some(content_type) = headers.get(header::CONTENT_TYPE)
+        && !content_type
+            .to_str()
+            .ok()
+            .and_then(|content_type| content_type.split(';').next())
+            .is_some_and(|media_type| {
+                media_type
+                    .trim()
+                    .eq_ignore_ascii_case("application/octet-stream")
+            })
+    {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    let mut body = Limited::new(body, state.max_upload_size(id));
+    let mut timings = ChunkTimings::new(Instant::now());
+    let received = async {
+        while let Some(frame) = body.frame().await {
+            match frame.map(Frame::into_data) {
+                Ok(Ok(chunk)) => timings.record(chunk.len()),
+                Ok(Err(_)) => (),
+                Err(error) if error.is::<LengthLimitError>() => {
+                    return Err(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+                // The client went away or sent a malformed body.
+                Err(_) => return Err(StatusCode::BAD_REQUEST),
+            }
+        }
+        Ok(())
+    }
+    .instrument(info_span!(parent: &state.span(id), "upload"))
+    .await;
+    if let Err(status) = received {
+        return status.into_response();
+    }
+    let (Some(upload_overall_bps), Some(upload_bps)) =
+        (timings.overall_bps(), timings.steady_state_bps())
+    else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    state.record_upload(id, timings.total(), upload_bps, upload_overall_bps);
+    let result = RawUploadResult {
+        id,
+        size: timings.total(),
+        upload_bps,
+        upload_overall_bps,
+        upload: bps_to_string(upload_bps),
+        upload_overall: bps_to_string(upload_overall_bps),
+    };
+    // Plain text unless JSON is preferred.
+    if accept_quality(&headers, "application/json").unwrap_or(0.0)
+        > accept_quality(&headers, "text/plain").unwrap_or(0.0)
+    {
+        (measurement_headers(), Json(result)).into_response()
+    } else {
+        (
+            measurement_headers(),
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            format!(
+                "Upload: {}\nUpload (overall): {}\n",
+                result.upload, result.upload_overall
+            ),
+        )
+            .into_response()
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct ResultsQuery {
+    profile: Option<String>,
     download: String,
     upload: String,
+    upload_overall: Option<String>,
     latency: String,
+    #[serde(default)]
+    possibly_proxied: bool,
+    provider: Option<String>,
+    location: Option<String>,
 }
 
 pub(crate) async fn results(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    tls: Option<Extension<TlsInfo>>,
+    version: Version,
+    headers: HeaderMap,
     Query(ResultsQuery {
+        profile,
         download,
         upload,
+        upload_overall,
         latency,
+        possibly_proxied,
+        provider,
+        location,
     }): Query<ResultsQuery>,
 ) -> impl IntoResponse {
     Html(
         ResultsTemplate {
+            profile,
             download,
             upload,
+            upload_overall,
             latency,
+            possibly_proxied,
+            provider,
+            location,
+            connection: ConnectionDetails::new(
+                &state,
+                addr,
+                &headers,
+                version,
+                tls.map(|Extension(tls)| tls),
+            ),
         }
         .render()
         .unwrap(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, http::Request, routing::put};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        config::Profile,
+        session::{TestResult, TestSource},
+    };
+
+    #[tokio::test]
+    async fn raw_upload_profile_limit() {
+        let mut state = AppState::for_tests();
+        state.profiles = Arc::new([
+            Arc::clone(&state.default_profile),
+            Arc::new(Profile {
+                name: "small".into(),
+                label: "Small".into(),
+                duration: 1,
+                start_size: 1_000,
+                max_size: 10_000,
+                streams: 1,
+                max_upload_size: 10_000,
+            }),
+        ]);
+        let id = Uuid::new_v4();
+        state.results.insert(
+            id,
+            TestResult {
+                source: TestSource::Terminal,
+                profile: Some("small".into()),
+                download_bps: Some(1_000_000.0),
+                latency: None,
+                upload_bps: None,
+                upload_overall_bps: None,
+                possibly_proxied: false,
+                client: None,
+                network: None,
+                finished: Instant::now(),
+                span: None,
+            },
+        );
+        let app = Router::new()
+            .route("/{id}/upload.bin", put(upload_raw))
+            .with_state(state);
+        let upload = async |id: Uuid| {
+            app.clone()
+                .oneshot(
+                    Request::builder()
+                        .method("PUT")
+                        .uri(format!("/{id}/upload.bin"))
+                        .body(Body::from(vec![0u8; 20_000]))
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+                .status()
+        };
+        assert_eq!(upload(id).await, StatusCode::PAYLOAD_TOO_LARGE);
+        // Without a session, the global limit of 1 MB applies.
+        assert_ne!(upload(Uuid::new_v4()).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
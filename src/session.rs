@@ -1,26 +1,42 @@
 use std::{
     net::IpAddr,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
     task::{Context, Poll, ready},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use ahash::RandomState;
 use bytes::Bytes;
 use dashmap::DashMap;
 use http_body::{Body as HttpBody, Frame};
-use tokio::sync::mpsc;
-use tracing::info;
+use serde::Serialize;
+use tokio::{sync::mpsc, task::AbortHandle};
+use tracing::{Span, info, info_span};
 use uuid::Uuid;
 
-use crate::utils::{bps_to_string, seconds_to_string};
+use crate::{
+    config::Profile,
+    geoip::{GeoIp, NetworkInfo},
+    payload::PayloadFormat,
+    privacy, telemetry, terminal,
+    utils::{bps_to_string, calculate_bps, seconds_to_string},
+};
 
 pub(crate) struct StreamingBody {
     rx: mpsc::Receiver<Bytes>,
     state: AppState,
     id: Uuid,
-    addr: IpAddr,
+    sent: Arc<AtomicUsize>,
+}
+
+impl StreamingBody {
+    pub(crate) fn sent(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.sent)
+    }
 }
 
 impl HttpBody for StreamingBody {
@@ -37,6 +53,7 @@ impl HttpBody for StreamingBody {
             if bytes.is_empty() {
                 None
             } else {
+                self.sent.fetch_add(bytes.len(), Ordering::Relaxed);
                 Some(Ok(Frame::data(bytes)))
             }
         }))
@@ -45,7 +62,9 @@ impl HttpBody for StreamingBody {
 
 impl Drop for StreamingBody {
     fn drop(&mut self) {
-        info!(id = %self.id, addr = %self.addr, "Disconnecting.");
+        self.state
+            .span(self.id)
+            .in_scope(|| info!("Disconnecting."));
         self.state.remove(self.id);
     }
 }
@@ -54,13 +73,39 @@ pub(crate) enum SessionState {
     Start,
     Downloading {
         start: Instant,
-        counter: usize,
+        profile: Arc<Profile>,
+        counters: Vec<usize>,
+        /// Steady-state duration and size of every download request, summed per stream.
+        steady: Vec<(Duration, usize)>,
         bandwidth_total: usize,
         bandwidth_elapsed: f64,
         latency_average: f64,
         latency_total_weights: f64,
     },
     End,
+    Cancelled,
+}
+
+/// Sum of the steady-state throughput of every stream, or the overall
+/// throughput if no request reported any timings.
+fn download_bps(
+    steady: &[(Duration, usize)],
+    bandwidth_total: usize,
+    bandwidth_elapsed: f64,
+) -> f64 {
+    let streams = steady
+        .iter()
+        .filter(|(duration, _)| !duration.is_zero())
+        .map(|&(duration, size)| calculate_bps(duration, size));
+    if streams.clone().next().is_some() {
+        streams.sum()
+    } else {
+        ((bandwidth_total * 8) as f64) / bandwidth_elapsed
+    }
+}
+
+fn session_span(id: Uuid, client: &str) -> Span {
+    info_span!(parent: None, "session", %id, addr = client)
 }
 
 #[derive(Clone)]
@@ -81,6 +126,18 @@ impl SessionSender {
     async fn finish(&self) {
         let _ = self.0.send(Bytes::new()).await;
     }
+
+    /// Sends without waiting, dropping `bytes` if the client is not keeping up.
+    fn try_send(&self, bytes: Bytes) {
+        debug_assert!(!bytes.is_empty(), "cannot send empty bytes");
+        let _ = self.0.try_send(bytes);
+    }
+
+    /// Closes the body without waiting. A client that is not keeping up is
+    /// closed once every sender is dropped instead.
+    fn try_finish(&self) {
+        let _ = self.0.try_send(Bytes::new());
+    }
 }
 
 impl<'a> SessionSenderPermit<'a> {
@@ -90,26 +147,176 @@ impl<'a> SessionSenderPermit<'a> {
     }
 }
 
+#[derive(Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub(crate) enum SessionSnapshot {
+    Start,
+    Downloading {
+        profile: String,
+        elapsed: f64,
+        counter: usize,
+        download_bytes: usize,
+        download_elapsed: f64,
+        download_bps: f64,
+        latency: f64,
+        latency_samples: usize,
+        possibly_proxied: bool,
+    },
+    End,
+    Cancelled,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TestSource {
+    Browser,
+    Terminal,
+    Iperf3,
+}
+
+impl TestSource {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            TestSource::Browser => "browser",
+            TestSource::Terminal => "terminal",
+            TestSource::Iperf3 => "iperf3",
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct TestResult {
+    pub(crate) source: TestSource,
+    pub(crate) profile: Option<String>,
+    pub(crate) download_bps: Option<f64>,
+    pub(crate) latency: Option<f64>,
+    pub(crate) upload_bps: Option<f64>,
+    pub(crate) upload_overall_bps: Option<f64>,
+    pub(crate) possibly_proxied: bool,
+    /// Anonymized client address, stripped after the address retention period.
+    pub(crate) client: Option<String>,
+    pub(crate) network: Option<NetworkInfo>,
+    #[serde(skip)]
+    pub(crate) finished: Instant,
+    /// Span of the session, kept until the upload test is recorded.
+    #[serde(skip)]
+    pub(crate) span: Option<Span>,
+}
+
+pub(crate) struct BandwidthMeasurement {
+    pub(crate) sender: SessionSender,
+    pub(crate) download: String,
+    pub(crate) latency: String,
+    pub(crate) start: Instant,
+    pub(crate) profile: Arc<Profile>,
+}
+
+/// Totals since the server started.
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) sessions: AtomicU64,
+    pub(crate) downloads: AtomicU64,
+    pub(crate) uploads: AtomicU64,
+    pub(crate) killed: AtomicU64,
+}
+
+/// Row of the admin dashboard.
+pub(crate) struct SessionOverview {
+    pub(crate) id: Uuid,
+    pub(crate) state: &'static str,
+    pub(crate) client: String,
+    pub(crate) elapsed: Duration,
+    pub(crate) download_bps: Option<f64>,
+    pub(crate) counter: usize,
+}
+
 pub(crate) struct SessionData {
     state: SessionState,
     sender: SessionSender,
+    task: Option<AbortHandle>,
+    possibly_proxied: bool,
+    /// Whether the body is a text/plain stream for a terminal client.
+    terminal: bool,
+    created: Instant,
+    /// Last change of state, from which idle sessions are expired.
+    updated: Instant,
+    /// Anonymized client address.
+    client: String,
+    network: Option<NetworkInfo>,
+    span: Span,
 }
 
 #[derive(Clone)]
 pub(crate) struct AppState {
     pub(crate) conn: Arc<DashMap<Uuid, SessionData, RandomState>>,
-    pub(crate) max_upload_size: String,
+    pub(crate) results: Arc<DashMap<Uuid, TestResult, RandomState>>,
+    pub(crate) profiles: Arc<[Arc<Profile>]>,
+    pub(crate) default_profile: Arc<Profile>,
+    pub(crate) max_upload_bytes: usize,
+    pub(crate) max_download_bytes: usize,
+    pub(crate) payload_format: PayloadFormat,
+    pub(crate) draining: Arc<AtomicBool>,
+    pub(crate) max_sessions: Option<usize>,
+    pub(crate) geoip: Option<Arc<GeoIp>>,
+    pub(crate) node_name: Arc<str>,
+    pub(crate) counters: Arc<Counters>,
 }
 
 impl AppState {
-    pub(crate) fn insert(&self, id: Uuid, addr: IpAddr) -> (SessionSender, StreamingBody) {
+    /// State with a single small profile, with the payload pool and the
+    /// privacy policy initialized.
+    #[cfg(test)]
+    pub(crate) fn for_tests() -> Self {
+        crate::payload::init_for_tests();
+        privacy::init(&crate::config::PrivacyConfig::default());
+        let profile = Arc::new(Profile {
+            name: "test".into(),
+            label: "Test".into(),
+            duration: 1,
+            start_size: 100_000,
+            max_size: 1_000_000,
+            streams: 1,
+            max_upload_size: 1_000_000,
+        });
+        AppState {
+            conn: Arc::default(),
+            results: Arc::default(),
+            profiles: Arc::new([Arc::clone(&profile)]),
+            default_profile: profile,
+            max_upload_bytes: 1_000_000,
+            max_download_bytes: 1_000_000,
+            payload_format: PayloadFormat::Binary,
+            draining: Arc::default(),
+            max_sessions: None,
+            geoip: None,
+            node_name: "".into(),
+            counters: Arc::default(),
+        }
+    }
+
+    pub(crate) fn insert(
+        &self,
+        id: Uuid,
+        addr: IpAddr,
+        terminal: bool,
+    ) -> (SessionSender, StreamingBody) {
         let (tx, rx) = mpsc::channel(128);
         let sender = SessionSender(tx);
+        let client = privacy::anonymize(addr);
+        self.counters.sessions.fetch_add(1, Ordering::Relaxed);
         self.conn.insert(
             id,
             SessionData {
                 state: SessionState::Start,
                 sender: sender.clone(),
+                task: None,
+                possibly_proxied: false,
+                terminal,
+                created: Instant::now(),
+                updated: Instant::now(),
+                span: session_span(id, &client),
+                client,
+                network: self.lookup_network(addr),
             },
         );
         (
@@ -117,21 +324,108 @@ impl AppState {
             StreamingBody {
                 rx,
                 state: self.clone(),
-                addr,
                 id,
+                sent: Arc::default(),
             },
         )
     }
 
-    pub(crate) fn start_download(&self, id: Uuid) -> Option<(SessionSender, Instant)> {
-        if let Some(mut session_data) = self.conn.get_mut(&id)
-            && let SessionData { state, sender, .. } = session_data.value_mut()
+    pub(crate) fn insert_detached(&self, id: Uuid, addr: IpAddr) {
+        let (tx, _) = mpsc::channel(1);
+        let client = privacy::anonymize(addr);
+        self.counters.sessions.fetch_add(1, Ordering::Relaxed);
+        self.conn.insert(
+            id,
+            SessionData {
+                state: SessionState::Start,
+                sender: SessionSender(tx),
+                task: None,
+                possibly_proxied: false,
+                terminal: false,
+                created: Instant::now(),
+                updated: Instant::now(),
+                span: session_span(id, &client),
+                client,
+                network: self.lookup_network(addr),
+            },
+        );
+    }
+
+    pub(crate) fn lookup_network(&self, addr: IpAddr) -> Option<NetworkInfo> {
+        self.geoip.as_ref().and_then(|geoip| geoip.lookup(addr))
+    }
+
+    pub(crate) fn network(&self, id: Uuid) -> Option<NetworkInfo> {
+        self.conn
+            .get(&id)
+            .and_then(|session_data| session_data.network.clone())
+    }
+
+    /// Span of the session or finished test `id`, for the requests and tasks
+    /// that belong to it.
+    pub(crate) fn span(&self, id: Uuid) -> Span {
+        if let Some(session_data) = self.conn.get(&id) {
+            session_data.span.clone()
+        } else {
+            self.results
+                .get(&id)
+                .and_then(|result| result.span.clone())
+                .unwrap_or_else(Span::none)
+        }
+    }
+
+    pub(crate) fn at_session_limit(&self) -> bool {
+        self.max_sessions
+            .is_some_and(|max_sessions| self.conn.len() >= max_sessions)
+    }
+
+    pub(crate) fn flag_proxied(&self, id: Uuid) {
+        if let Some(mut session_data) = self.conn.get_mut(&id) {
+            session_data.possibly_proxied = true;
+        }
+    }
+
+    pub(crate) fn profile(&self, name: Option<&str>) -> Option<Arc<Profile>> {
+        match name {
+            Some(name) => self
+                .profiles
+                .iter()
+                .find(|profile| profile.name == name)
+                .cloned(),
+            None => Some(Arc::clone(&self.default_profile)),
+        }
+    }
+
+    /// Upload limit of the profile that session `id` was tested with.
+    pub(crate) fn max_upload_size(&self, id: Uuid) -> usize {
+        self.results
+            .get(&id)
+            .and_then(|result| self.profile(result.profile.as_deref()))
+            .map_or(self.max_upload_bytes, |profile| profile.max_upload_size)
+    }
+
+    pub(crate) fn start_download(
+        &self,
+        id: Uuid,
+        profile: Arc<Profile>,
+    ) -> Option<(SessionSender, Instant)> {
+        if !self.draining.load(Ordering::Acquire)
+            && let Some(mut session_data) = self.conn.get_mut(&id)
+            && let SessionData {
+                state,
+                sender,
+                updated,
+                ..
+            } = session_data.value_mut()
             && let SessionState::Start = state
         {
             let start = Instant::now();
+            *updated = start;
             *state = SessionState::Downloading {
                 start,
-                counter: 0,
+                counters: vec![0; profile.streams],
+                steady: vec![Default::default(); profile.streams],
+                profile,
                 bandwidth_total: 0,
                 bandwidth_elapsed: 0.000001,
                 latency_average: 0.0,
@@ -143,16 +437,23 @@ impl AppState {
         }
     }
 
-    pub(crate) fn measure_download_latency(&self, id: Uuid, timestamp: f64, counter: usize) {
+    pub(crate) fn measure_download_latency(
+        &self,
+        id: Uuid,
+        timestamp: f64,
+        stream: usize,
+        counter: usize,
+    ) {
         if let Some(mut session_data) = self.conn.get_mut(&id)
             && let SessionData { state, .. } = session_data.value_mut()
             && let SessionState::Downloading {
                 start,
-                counter: session_counter,
+                counters,
                 latency_average: average,
                 latency_total_weights: total_weights,
                 ..
             } = state
+            && let Some(session_counter) = counters.get_mut(stream)
             && counter == *session_counter + 1
         {
             let latency = (start.elapsed().as_secs_f64() - timestamp) / 2.0;
@@ -168,28 +469,39 @@ impl AppState {
         &self,
         id: Uuid,
         size: usize,
+        steady_state: Option<(Duration, usize)>,
+        stream: usize,
         counter: usize,
-    ) -> Option<(SessionSender, String, String, Instant)> {
+    ) -> Option<BandwidthMeasurement> {
         if let Some(mut session_data) = self.conn.get_mut(&id)
             && let SessionData { state, sender, .. } = session_data.value_mut()
             && let SessionState::Downloading {
                 start,
+                profile,
                 bandwidth_total,
                 bandwidth_elapsed,
                 latency_average,
-                counter: session_counter,
+                counters,
+                steady,
                 ..
             } = state
-            && counter == *session_counter
+            && counters.get(stream) == Some(&counter)
         {
             *bandwidth_total += size;
             *bandwidth_elapsed = start.elapsed().as_secs_f64();
-            Some((
-                sender.clone(),
-                bps_to_string(((*bandwidth_total * 8) as f64) / *bandwidth_elapsed),
-                seconds_to_string(*latency_average),
-                *start,
-            ))
+            if let Some((duration, size)) = steady_state
+                && let Some((stream_duration, stream_size)) = steady.get_mut(stream)
+            {
+                *stream_duration += duration;
+                *stream_size += size;
+            }
+            Some(BandwidthMeasurement {
+                sender: sender.clone(),
+                download: bps_to_string(download_bps(steady, *bandwidth_total, *bandwidth_elapsed)),
+                latency: seconds_to_string(*latency_average),
+                start: *start,
+                profile: Arc::clone(profile),
+            })
         } else {
             None
         }
@@ -197,19 +509,249 @@ impl AppState {
 
     pub(crate) fn stop_download(&self, id: Uuid) -> Option<(String, String)> {
         if let Some(mut session_data) = self.conn.get_mut(&id)
-            && let SessionData { state, .. } = session_data.value_mut()
+            && let SessionData { state, updated, .. } = session_data.value_mut()
             && let SessionState::Downloading {
+                steady,
                 bandwidth_total,
                 bandwidth_elapsed,
                 latency_average,
                 ..
             } = state
         {
-            let download_bandwidth =
-                bps_to_string(((*bandwidth_total * 8) as f64) / *bandwidth_elapsed);
-            let download_latency = seconds_to_string(*latency_average);
-            *state = SessionState::End;
-            Some((download_bandwidth, download_latency))
+            let download_bps = download_bps(steady, *bandwidth_total, *bandwidth_elapsed);
+            let latency = *latency_average;
+            let downloading = std::mem::replace(state, SessionState::End);
+            *updated = Instant::now();
+            self.insert_result(
+                id,
+                TestSource::Browser,
+                downloading,
+                download_bps,
+                latency,
+                &session_data,
+            );
+            Some((bps_to_string(download_bps), seconds_to_string(latency)))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn update_stream_download(&self, id: Uuid, total: usize) {
+        if let Some(mut session_data) = self.conn.get_mut(&id)
+            && let SessionData { state, .. } = session_data.value_mut()
+            && let SessionState::Downloading {
+                start,
+                bandwidth_total,
+                bandwidth_elapsed,
+                ..
+            } = state
+        {
+            *bandwidth_total = total;
+            *bandwidth_elapsed = start.elapsed().as_secs_f64();
+        }
+    }
+
+    pub(crate) fn stop_stream_download(&self, id: Uuid, download_bps: f64, latency: f64) {
+        if let Some(mut session_data) = self.conn.get_mut(&id)
+            && let SessionData { state, updated, .. } = session_data.value_mut()
+            && let SessionState::Downloading { .. } = state
+        {
+            let downloading = std::mem::replace(state, SessionState::End);
+            *updated = Instant::now();
+            self.insert_result(
+                id,
+                TestSource::Terminal,
+                downloading,
+                download_bps,
+                latency,
+                &session_data,
+            );
+        }
+    }
+
+    /// Records the result of a session that just left the `downloading` state.
+    fn insert_result(
+        &self,
+        id: Uuid,
+        source: TestSource,
+        downloading: SessionState,
+        download_bps: f64,
+        latency: f64,
+        session_data: &SessionData,
+    ) {
+        let SessionState::Downloading {
+            profile,
+            bandwidth_total: download_bytes,
+            ..
+        } = downloading
+        else {
+            return;
+        };
+        session_data.span.in_scope(|| {
+            info!(
+                source = source.as_str(),
+                profile = profile.name,
+                download_bytes,
+                download_bps,
+                latency,
+                possibly_proxied = session_data.possibly_proxied,
+                "Download test finished."
+            )
+        });
+        telemetry::record_download(source, download_bps, Some(latency));
+        self.counters.downloads.fetch_add(1, Ordering::Relaxed);
+        self.results.insert(
+            id,
+            TestResult {
+                source,
+                profile: Some(profile.name.clone()),
+                download_bps: Some(download_bps),
+                latency: Some(latency),
+                upload_bps: None,
+                upload_overall_bps: None,
+                possibly_proxied: session_data.possibly_proxied,
+                client: Some(session_data.client.clone()),
+                network: session_data.network.clone(),
+                finished: Instant::now(),
+                span: Some(session_data.span.clone()),
+            },
+        );
+    }
+
+    /// Logs an upload to result `id`, and stores its figures if the result is
+    /// still awaiting its upload, so that later uploads cannot overwrite them.
+    pub(crate) fn record_upload(
+        &self,
+        id: Uuid,
+        upload_bytes: usize,
+        upload_bps: f64,
+        upload_overall_bps: f64,
+    ) {
+        let Some(mut result) = self.results.get_mut(&id) else {
+            return;
+        };
+        if result.upload_bps.is_none() {
+            result.upload_bps = Some(upload_bps);
+            result.upload_overall_bps = Some(upload_overall_bps);
+            telemetry::record_upload(result.source, upload_bps);
+            self.counters.uploads.fetch_add(1, Ordering::Relaxed);
+        }
+        result.span.clone().unwrap_or_else(Span::none).in_scope(|| {
+            info!(
+                source = result.source.as_str(),
+                profile = result.profile,
+                download_bps = result.download_bps,
+                latency = result.latency,
+                upload_bytes,
+                upload_bps,
+                upload_overall_bps,
+                possibly_proxied = result.possibly_proxied,
+                "Upload test finished."
+            )
+        });
+    }
+
+    pub(crate) fn snapshot(&self, id: Uuid) -> Option<SessionSnapshot> {
+        let session_data = self.conn.get(&id)?;
+        Some(match &session_data.state {
+            SessionState::Start => SessionSnapshot::Start,
+            SessionState::Downloading {
+                start,
+                profile,
+                counters,
+                steady,
+                bandwidth_total,
+                bandwidth_elapsed,
+                latency_average,
+                latency_total_weights,
+            } => SessionSnapshot::Downloading {
+                profile: profile.name.clone(),
+                elapsed: start.elapsed().as_secs_f64(),
+                counter: counters.iter().sum(),
+                download_bytes: *bandwidth_total,
+                download_elapsed: *bandwidth_elapsed,
+                download_bps: download_bps(steady, *bandwidth_total, *bandwidth_elapsed),
+                latency: *latency_average,
+                latency_samples: *latency_total_weights as usize,
+                possibly_proxied: session_data.possibly_proxied,
+            },
+            SessionState::End => SessionSnapshot::End,
+            SessionState::Cancelled => SessionSnapshot::Cancelled,
+        })
+    }
+
+    /// Open sessions, oldest first.
+    pub(crate) fn overview(&self) -> Vec<SessionOverview> {
+        let mut sessions: Vec<_> = self
+            .conn
+            .iter()
+            .map(|session_data| {
+                let (state, download_bps, counter) = match &session_data.state {
+                    SessionState::Start => ("start", None, 0),
+                    SessionState::Downloading {
+                        counters,
+                        steady,
+                        bandwidth_total,
+                        bandwidth_elapsed,
+                        ..
+                    } => (
+                        "downloading",
+                        Some(download_bps(steady, *bandwidth_total, *bandwidth_elapsed)),
+                        counters.iter().sum(),
+                    ),
+                    SessionState::End => ("end", None, 0),
+                    SessionState::Cancelled => ("cancelled", None, 0),
+                };
+                SessionOverview {
+                    id: *session_data.key(),
+                    state,
+                    client: session_data.client.clone(),
+                    elapsed: session_data.created.elapsed(),
+                    download_bps,
+                    counter,
+                }
+            })
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.elapsed));
+        sessions
+    }
+
+    /// Deletes results past the retention period, and strips the client
+    /// address from those past the address retention period.
+    pub(crate) fn purge_results(&self) {
+        let policy = privacy::policy();
+        self.results.retain(|_, result| {
+            let age = result.finished.elapsed();
+            if age >= policy.ip_retention {
+                result.client = None;
+            }
+            age < policy.results_retention
+        });
+    }
+
+    pub(crate) fn set_task(&self, id: Uuid, task: AbortHandle) {
+        if let Some(mut session_data) = self.conn.get_mut(&id) {
+            session_data.task = Some(task);
+        }
+    }
+
+    pub(crate) fn cancel(&self, id: Uuid) -> Option<SessionSender> {
+        if let Some(mut session_data) = self.conn.get_mut(&id)
+            && let SessionData {
+                state,
+                sender,
+                task,
+                updated,
+                ..
+            } = session_data.value_mut()
+            && let SessionState::Start | SessionState::Downloading { .. } = state
+        {
+            *state = SessionState::Cancelled;
+            *updated = Instant::now();
+            if let Some(task) = task.take() {
+                task.abort();
+            }
+            Some(sender.clone())
         } else {
             None
         }
@@ -224,7 +766,87 @@ impl AppState {
         }
     }
 
+    /// Refuses new tests and sends `html` to every browser session, closing the
+    /// ones without a running test. Clients that are not keeping up miss the
+    /// page rather than holding up the shutdown.
+    pub(crate) fn drain(&self, html: Bytes) {
+        self.draining.store(true, Ordering::Release);
+        for session_data in self
+            .conn
+            .iter()
+            .filter(|session_data| !session_data.terminal)
+        {
+            session_data.sender.try_send(html.clone());
+            if !matches!(session_data.state, SessionState::Downloading { .. }) {
+                session_data.sender.try_finish();
+            }
+        }
+    }
+
+    /// Sends `html` to and closes every session that has not started or
+    /// finished a test for `timeout`, leaving running tests alone, and ends the
+    /// spans of results that are no longer expected to get an upload. Terminal
+    /// sessions are closed without the page, and clients that are not keeping
+    /// up miss it rather than holding up the expiry of the others.
+    pub(crate) fn expire_idle(&self, timeout: Duration, html: &Bytes) {
+        let sessions: Vec<_> = self
+            .conn
+            .iter()
+            .filter(|session_data| {
+                !matches!(session_data.state, SessionState::Downloading { .. })
+                    && session_data.updated.elapsed() >= timeout
+            })
+            .map(|session_data| {
+                (
+                    *session_data.key(),
+                    session_data.sender.clone(),
+                    session_data.terminal,
+                )
+            })
+            .collect();
+        for (id, sender, terminal) in sessions {
+            self.span(id).in_scope(|| info!("Session expired."));
+            if !terminal {
+                sender.try_send(html.clone());
+            }
+            sender.try_finish();
+            self.remove(id);
+        }
+        for mut result in self.results.iter_mut() {
+            if result.finished.elapsed() >= timeout {
+                result.span = None;
+            }
+        }
+    }
+
+    /// Stops the test of session `id`, sends it `html`, or `text` if it is a
+    /// terminal session, and closes it. A client that is not keeping up misses
+    /// the message rather than holding up the caller.
+    pub(crate) fn kill(&self, id: Uuid, html: &Bytes, text: &str) -> bool {
+        let Some((sender, terminal)) = self
+            .conn
+            .get(&id)
+            .map(|session_data| (session_data.sender.clone(), session_data.terminal))
+        else {
+            return false;
+        };
+        self.span(id).in_scope(|| info!("Session killed."));
+        self.counters.killed.fetch_add(1, Ordering::Relaxed);
+        self.remove(id);
+        if terminal {
+            sender.try_send(terminal::message(text));
+        } else {
+            sender.try_send(html.clone());
+        }
+        sender.try_finish();
+        true
+    }
+
     pub(crate) fn remove(&self, id: Uuid) {
-        self.conn.remove(&id);
+        if let Some((_, session_data)) = self.conn.remove(&id)
+            && let Some(task) = session_data.task
+        {
+            task.abort();
+        }
     }
 }
@@ -0,0 +1,58 @@
+use std::{
+    os::fd::{FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+use color_eyre::eyre::Context;
+use sd_notify::NotifyState;
+use tokio::time::{Interval, interval};
+use tracing::warn;
+
+/// Sockets passed by systemd through socket activation, in the order of the
+/// `ListenStream=` lines of the socket unit.
+///
+/// The activation variables are unset so that the sockets cannot be taken
+/// twice, which is only sound before any other thread may read the
+/// environment, so this must be called before the runtime starts.
+pub(crate) fn listen_fds() -> color_eyre::Result<Vec<OwnedFd>> {
+    let fds = sd_notify::listen_fds().wrap_err("invalid socket activation variables")?;
+    // SAFETY: systemd hands over ownership of these file descriptors.
+    Ok(fds.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }).collect())
+}
+
+fn notify(state: NotifyState) {
+    if let Err(error) = sd_notify::notify(false, &[state]) {
+        warn!(%error, "Failed to notify systemd.");
+    }
+}
+
+/// Reports readiness, returning the watchdog to ping if the unit enables it.
+pub(crate) fn ready() -> Watchdog {
+    notify(NotifyState::Ready);
+    let mut usec = 0;
+    Watchdog(
+        sd_notify::watchdog_enabled(false, &mut usec)
+            .then(|| interval(Duration::from_micros(usec) / 2)),
+    )
+}
+
+/// Pinged from the accept loop rather than a task of its own, so that systemd
+/// restarts a server that stopped handling connections.
+pub(crate) struct Watchdog(Option<Interval>);
+
+impl Watchdog {
+    /// Waits for the next ping and sends it, or never returns if disabled.
+    pub(crate) async fn ping(&mut self) {
+        match &mut self.0 {
+            Some(interval) => {
+                interval.tick().await;
+                notify(NotifyState::Watchdog);
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+pub(crate) fn stopping() {
+    notify(NotifyState::Stopping);
+}
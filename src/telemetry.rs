@@ -0,0 +1,310 @@
+use std::{sync::LazyLock, time::Duration};
+
+use color_eyre::eyre::Context;
+use opentelemetry::{
+    KeyValue, global,
+    metrics::{Counter, Histogram},
+    trace::TracerProvider as _,
+};
+use opentelemetry_otlp::{MetricExporter, Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    metrics::{PeriodicReader, SdkMeterProvider},
+    trace::{SdkTracer, SdkTracerProvider},
+};
+use tracing::warn;
+
+use crate::{
+    config::{OtlpConfig, OtlpProtocol},
+    session::{AppState, TestSource},
+};
+
+static INSTRUMENTATION_SCOPE: &str = "no-js-speedtest";
+
+/// Exporters of traces and metrics to an OpenTelemetry collector.
+pub(crate) struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Telemetry {
+    /// Sets up the exporters and installs the global meter provider. Must be
+    /// called from within the Tokio runtime.
+    pub(crate) fn init(config: &OtlpConfig) -> color_eyre::Result<Self> {
+        let (span_exporter, metric_exporter) = match config.protocol {
+            OtlpProtocol::Grpc => {
+                let mut span_exporter = SpanExporter::builder().with_tonic();
+                let mut metric_exporter = MetricExporter::builder().with_tonic();
+                if let Some(endpoint) = &config.endpoint {
+                    span_exporter = span_exporter.with_endpoint(endpoint);
+                    metric_exporter = metric_exporter.with_endpoint(endpoint);
+                }
+                (span_exporter.build(), metric_exporter.build())
+            }
+            OtlpProtocol::Http => {
+                let mut span_exporter = SpanExporter::builder()
+                    .with_http()
+                    .with_protocol(Protocol::HttpBinary);
+                let mut metric_exporter = MetricExporter::builder()
+                    .with_http()
+                    .with_protocol(Protocol::HttpBinary);
+                // Signal paths are only appended to endpoints from the environment.
+                if let Some(endpoint) = &config.endpoint {
+                    let endpoint = endpoint.trim_end_matches('/');
+                    span_exporter = span_exporter.with_endpoint(format!("{endpoint}/v1/traces"));
+                    metric_exporter =
+                        metric_exporter.with_endpoint(format!("{endpoint}/v1/metrics"));
+                }
+                (span_exporter.build(), metric_exporter.build())
+            }
+        };
+        let resource = Resource::builder()
+            .with_service_name(config.service_name.clone())
+            .build();
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(span_exporter.wrap_err("failed to create OTLP span exporter")?)
+            .with_resource(resource.clone())
+            .build();
+        let reader = PeriodicReader::builder(
+            metric_exporter.wrap_err("failed to create OTLP metric exporter")?,
+        )
+        .with_interval(Duration::from_secs(config.metrics_interval))
+        .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+        global::set_meter_provider(meter_provider.clone());
+        Ok(Telemetry {
+            tracer_provider,
+            meter_provider,
+        })
+    }
+
+    pub(crate) fn tracer(&self) -> SdkTracer {
+        self.tracer_provider.tracer(INSTRUMENTATION_SCOPE)
+    }
+
+    /// Exports pending spans and metrics, blocking until done.
+    pub(crate) fn shutdown(self) {
+        if let Err(error) = self.tracer_provider.shutdown() {
+            warn!(%error, "Failed to export remaining spans.");
+        }
+        if let Err(error) = self.meter_provider.shutdown() {
+            warn!(%error, "Failed to export remaining metrics.");
+        }
+    }
+}
+
+struct Metrics {
+    tests: Counter<u64>,
+    download: Histogram<f64>,
+    upload: Histogram<f64>,
+    latency: Histogram<f64>,
+}
+
+/// Instruments of the global meter provider, which does nothing unless
+/// [`Telemetry::init`] installed one before the first test.
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let meter = global::meter(INSTRUMENTATION_SCOPE);
+    Metrics {
+        tests: meter
+            .u64_counter("speedtest.tests")
+            .with_description("Finished download and upload tests.")
+            .with_unit("{test}")
+            .build(),
+        download: meter
+            .f64_histogram("speedtest.download.throughput")
+            .with_description("Download throughput of finished tests.")
+            .with_unit("bit/s")
+            .build(),
+        upload: meter
+            .f64_histogram("speedtest.upload.throughput")
+            .with_description("Upload throughput of finished tests.")
+            .with_unit("bit/s")
+            .build(),
+        latency: meter
+            .f64_histogram("speedtest.latency")
+            .with_description("Latency measured during download tests.")
+            .with_unit("s")
+            .build(),
+    }
+});
+
+pub(crate) fn record_download(source: TestSource, download_bps: f64, latency: Option<f64>) {
+    let attributes = [
+        KeyValue::new("source", source.as_str()),
+        KeyValue::new("direction", "download"),
+    ];
+    METRICS.tests.add(1, &attributes);
+    METRICS.download.record(download_bps, &attributes[..1]);
+    if let Some(latency) = latency {
+        METRICS.latency.record(latency, &attributes[..1]);
+    }
+}
+
+pub(crate) fn record_upload(source: TestSource, upload_bps: f64) {
+    let attributes = [
+        KeyValue::new("source", source.as_str()),
+        KeyValue::new("direction", "upload"),
+    ];
+    METRICS.tests.add(1, &attributes);
+    METRICS.upload.record(upload_bps, &attributes[..1]);
+}
+
+/// Reports the number of open sessions on every export.
+pub(crate) fn observe_sessions(state: AppState) {
+    global::meter(INSTRUMENTATION_SCOPE)
+        .u64_observable_gauge("speedtest.sessions")
+        .with_description("Open test sessions.")
+        .with_unit("{session}")
+        .with_callback(move |observer| observer.observe(state.conn.len() as u64, &[]))
+        .build();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        Extension, Router,
+        body::Body,
+        extract::ConnectInfo,
+        http::{Request, StatusCode, header},
+        routing::{get, post, put},
+    };
+    use bytes::Bytes;
+    use http_body_util::BodyExt;
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use prost::Message;
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::{payload::PayloadFormat, routes};
+
+    /// OTLP/HTTP collector keeping the bodies of trace exports.
+    async fn collector() -> (String, Arc<Mutex<Vec<Bytes>>>) {
+        let exports = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route(
+                "/v1/traces",
+                post({
+                    let exports = Arc::clone(&exports);
+                    async move |body: Bytes| exports.lock().unwrap().push(body)
+                }),
+            )
+            .route("/v1/metrics", post(async || {}));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (endpoint, exports)
+    }
+
+    fn request(method: &str, uri: String, body: Body) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::USER_AGENT, "Mozilla/5.0")
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(body)
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_test_trace() {
+        let state = AppState::for_tests();
+        let (endpoint, exports) = collector().await;
+        let telemetry = Telemetry::init(&OtlpConfig {
+            endpoint: Some(endpoint),
+            protocol: OtlpProtocol::Http,
+            ..OtlpConfig::default()
+        })
+        .unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(telemetry.tracer()));
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route("/", get(routes::index))
+            .route("/{id}/start.jpg", get(routes::start))
+            .route(
+                "/{id}/download.bin",
+                get(routes::download).layer(Extension(PayloadFormat::Binary)),
+            )
+            .route("/{id}/upload.bin", put(routes::upload_raw))
+            .layer(Extension(ConnectInfo(SocketAddr::from((
+                [192, 0, 2, 1],
+                1234,
+            )))))
+            .with_state(state.clone());
+        let index = app
+            .clone()
+            .oneshot(request("GET", "/".into(), Body::empty()))
+            .await
+            .unwrap();
+        let id = *state.conn.iter().next().unwrap().key();
+        app.clone()
+            .oneshot(request("GET", format!("/{id}/start.jpg"), Body::empty()))
+            .await
+            .unwrap();
+        let download = app
+            .clone()
+            .oneshot(request(
+                "GET",
+                format!("/{id}/download.bin?size=100000&i=0&ts=0"),
+                Body::empty(),
+            ))
+            .await
+            .unwrap();
+        download.into_body().collect().await.unwrap();
+        while !state.results.contains_key(&id) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let upload = app
+            .oneshot(request(
+                "PUT",
+                format!("/{id}/upload.bin"),
+                Body::from(vec![0u8; 100_000]),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(upload.status(), StatusCode::OK);
+        // Closing the page and forgetting the result ends the session span.
+        drop(index);
+        state.results.clear();
+        drop(guard);
+        tokio::task::spawn_blocking(move || telemetry.shutdown())
+            .await
+            .unwrap();
+
+        let spans: Vec<_> = exports
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|body| {
+                ExportTraceServiceRequest::decode(body.as_ref())
+                    .unwrap()
+                    .resource_spans
+            })
+            .flat_map(|resource_spans| resource_spans.scope_spans)
+            .flat_map(|scope_spans| scope_spans.spans)
+            .collect();
+        let session = spans
+            .iter()
+            .find(|span| span.name == "session")
+            .expect("session span");
+        assert!(session.parent_span_id.is_empty());
+        for name in ["start", "download", "upload"] {
+            let span = spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap_or_else(|| panic!("{name} span"));
+            assert_eq!(span.trace_id, session.trace_id, "{name}");
+            assert_eq!(span.parent_span_id, session.span_id, "{name}");
+        }
+    }
+}
@@ -1,15 +1,29 @@
+use std::sync::Arc;
+
 use askama::Template;
 use uuid::Uuid;
 
+use crate::{config::Profile, connection::ConnectionDetails};
+
 #[derive(Template)]
 #[template(path = "index.html")]
-pub(crate) struct IndexTemplate {
+pub(crate) struct IndexTemplate<'a> {
     pub(crate) id: Uuid,
+    pub(crate) profiles: &'a [Arc<Profile>],
+    pub(crate) format: &'static str,
+    pub(crate) provider: Option<String>,
+    pub(crate) location: Option<String>,
+    pub(crate) connection: ConnectionDetails,
 }
 
 #[derive(Template)]
 #[template(path = "privacy.html")]
-pub(crate) struct PrivacyTemplate;
+pub(crate) struct PrivacyTemplate {
+    pub(crate) anonymization: &'static str,
+    pub(crate) results_retention: String,
+    pub(crate) ip_retention: Option<String>,
+    pub(crate) geoip: bool,
+}
 
 #[derive(Template)]
 #[template(path = "start_download.html")]
@@ -17,6 +31,8 @@ pub(crate) struct StartDownloadTemplate {
     pub(crate) id: Uuid,
     pub(crate) test_duration: u64,
     pub(crate) start_size: usize,
+    pub(crate) extension: &'static str,
+    pub(crate) streams: usize,
     pub(crate) timestamp: f64,
 }
 
@@ -25,6 +41,8 @@ pub(crate) struct StartDownloadTemplate {
 pub(crate) struct DownloadTemplate {
     pub(crate) id: Uuid,
     pub(crate) next_size: usize,
+    pub(crate) extension: &'static str,
+    pub(crate) stream: usize,
     pub(crate) counter: usize,
     pub(crate) timestamp: f64,
     pub(crate) download: String,
@@ -34,15 +52,107 @@ pub(crate) struct DownloadTemplate {
 #[derive(Template)]
 #[template(path = "finish_download.html")]
 pub(crate) struct FinishDownloadTemplate {
+    pub(crate) id: Uuid,
     pub(crate) download: String,
     pub(crate) latency: String,
     pub(crate) max_upload_size: String,
 }
 
+#[derive(Template)]
+#[template(path = "cancel.html")]
+pub(crate) struct CancelTemplate;
+
+#[derive(Template)]
+#[template(path = "expired.html")]
+pub(crate) struct ExpiredTemplate;
+
+#[derive(Template)]
+#[template(path = "killed.html")]
+pub(crate) struct KilledTemplate;
+
+#[derive(Template)]
+#[template(path = "restarting.html")]
+pub(crate) struct RestartingTemplate;
+
 #[derive(Template)]
 #[template(path = "results.html")]
 pub(crate) struct ResultsTemplate {
+    pub(crate) profile: Option<String>,
     pub(crate) download: String,
     pub(crate) upload: String,
+    pub(crate) upload_overall: Option<String>,
     pub(crate) latency: String,
+    pub(crate) possibly_proxied: bool,
+    pub(crate) provider: Option<String>,
+    pub(crate) location: Option<String>,
+    pub(crate) connection: ConnectionDetails,
 }
+
+#[derive(Template)]
+#[template(path = "basic/index.html")]
+pub(crate) struct BasicIndexTemplate<'a> {
+    pub(crate) profiles: &'a [Arc<Profile>],
+}
+
+#[derive(Template)]
+#[template(path = "basic/download_header.html")]
+pub(crate) struct BasicDownloadHeaderTemplate {
+    pub(crate) profile: String,
+    pub(crate) progress: u32,
+    pub(crate) download: String,
+    pub(crate) latency: String,
+}
+
+#[derive(Template)]
+#[template(path = "basic/download_footer.html")]
+pub(crate) struct BasicDownloadFooterTemplate {
+    pub(crate) id: Uuid,
+    pub(crate) next_size: usize,
+    pub(crate) counter: usize,
+    pub(crate) timestamp: f64,
+    pub(crate) download: String,
+    pub(crate) latency: String,
+}
+
+#[derive(Template)]
+#[template(path = "basic/results.html")]
+pub(crate) struct BasicResultsTemplate {
+    pub(crate) id: Uuid,
+    pub(crate) download: String,
+    pub(crate) latency: String,
+    pub(crate) max_upload_size: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin/index.html")]
+pub(crate) struct AdminIndexTemplate;
+
+pub(crate) struct AdminSessionRow {
+    pub(crate) id: Uuid,
+    pub(crate) short_id: String,
+    pub(crate) state: &'static str,
+    pub(crate) client: String,
+    pub(crate) elapsed: String,
+    pub(crate) throughput: String,
+    pub(crate) counter: usize,
+}
+
+#[derive(Template)]
+#[template(path = "admin/snapshot.html")]
+pub(crate) struct AdminSnapshotTemplate {
+    pub(crate) sessions: Vec<AdminSessionRow>,
+    pub(crate) open_sessions: usize,
+    pub(crate) max_sessions: Option<usize>,
+    pub(crate) running_tests: usize,
+    pub(crate) stored_results: usize,
+    pub(crate) sessions_total: u64,
+    pub(crate) downloads: u64,
+    pub(crate) uploads: u64,
+    pub(crate) killed: u64,
+    pub(crate) draining: bool,
+    pub(crate) can_kill: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin/paused.html")]
+pub(crate) struct AdminPausedTemplate;
@@ -0,0 +1,228 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::http::{HeaderMap, header};
+use bytes::Bytes;
+use tokio::time::interval;
+use uuid::Uuid;
+
+use crate::{
+    connection::ConnectionSocket,
+    measure::ChunkTimings,
+    payload::PayloadStream,
+    session::{AppState, SessionSender},
+    utils::{bps_to_string, seconds_to_string},
+};
+
+static TERMINAL_USER_AGENTS: [&str; 4] = ["curl/", "Wget/", "HTTPie/", "xh/"];
+static PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+static PROGRESS_BAR_WIDTH: usize = 30;
+static CHUNK_SIZE: usize = 64 * 1024;
+/// The payload is sent as an application program command, which terminals
+/// consume without drawing anything, so that rendering does not limit the
+/// measured speed. Only the progress line is printed, once per interval.
+static PAYLOAD_START: &str = "\x1b_nojs-speedtest;";
+static PAYLOAD_END: &str = "\x1b\\";
+
+pub(crate) fn is_terminal_client(headers: &HeaderMap) -> bool {
+    if let Some(user_agent) = headers.get(header::USER_AGENT)
+        && let Ok(user_agent) = user_agent.to_str()
+        && TERMINAL_USER_AGENTS
+            .iter()
+            .any(|prefix| user_agent.starts_with(prefix))
+    {
+        return true;
+    }
+    if let Some(accept) = headers.get(header::ACCEPT)
+        && let Ok(accept) = accept.to_str()
+    {
+        accept.contains("text/plain") && !accept.contains("text/html")
+    } else {
+        false
+    }
+}
+
+fn progress_line(elapsed: Duration, duration: Duration, speed: Option<f64>) -> String {
+    let progress = (elapsed.as_secs_f64() / duration.as_secs_f64()).min(1.0);
+    let filled = (progress * PROGRESS_BAR_WIDTH as f64) as usize;
+    format!(
+        "\r[{}{}] {:>3}%  {:<14}",
+        "#".repeat(filled),
+        " ".repeat(PROGRESS_BAR_WIDTH - filled),
+        (progress * 100.0) as u32,
+        speed.map(bps_to_string).unwrap_or_else(|| "--".into()),
+    )
+}
+
+/// Ends the payload string, prints the progress line and starts the next
+/// payload string.
+fn progress_update(line: &str) -> Bytes {
+    Bytes::from(format!("{PAYLOAD_END}{line}{PAYLOAD_START}"))
+}
+
+/// Text sent to a terminal session by the server, ending the payload string
+/// it may interrupt.
+pub(crate) fn message(text: &str) -> Bytes {
+    Bytes::from(format!("{PAYLOAD_END}\n\n{text}\n"))
+}
+
+pub(crate) async fn run(
+    state: AppState,
+    id: Uuid,
+    sender: SessionSender,
+    sent: Arc<AtomicUsize>,
+    socket: Option<ConnectionSocket>,
+    upload_url: String,
+) {
+    let profile = Arc::clone(&state.default_profile);
+    let duration = Duration::from_secs(profile.duration);
+    let Some((_, start)) = state.start_download(id, profile) else {
+        return;
+    };
+    sender
+        .send(Bytes::from_static(
+            b"NoJS Speedtest\n\nTesting download...\n",
+        ))
+        .await;
+    let initial = sent.load(Ordering::Relaxed);
+    let mut timings = ChunkTimings::new(start);
+    let mut ticker = interval(PROGRESS_INTERVAL);
+    let mut payload = PayloadStream::text(usize::MAX);
+    let mut update = Some(Bytes::from(
+        progress_line(Duration::ZERO, duration, None) + PAYLOAD_START,
+    ));
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let total = sent.load(Ordering::Relaxed) - initial;
+                timings.record(total - timings.total());
+                state.update_stream_download(id, total);
+                if start.elapsed() >= duration {
+                    break;
+                }
+                let line = progress_line(start.elapsed(), duration, timings.overall_bps());
+                update = Some(progress_update(&line));
+            }
+            permit = sender.reserve() => match permit {
+                Some(permit) => match update.take() {
+                    Some(update) => permit.send(update),
+                    // The stream never runs out, and an empty chunk would end the body.
+                    None => permit.send(payload.next_chunk(CHUNK_SIZE).unwrap()),
+                },
+                None => return,
+            }
+        }
+    }
+
+    let download = timings.steady_state_bps().unwrap_or_default();
+    let latency = socket
+        .and_then(|socket| socket.rtt())
+        .map(|rtt| rtt.as_secs_f64() / 2.0)
+        .unwrap_or_default();
+    state.stop_stream_download(id, download, latency);
+    let summary = format!(
+        "{PAYLOAD_END}{}\n\nDownload: {}\nLatency: {}\n\nTo test your upload speed, send us a large file:\n  curl -T /path/to/file {upload_url}\n",
+        progress_line(start.elapsed(), duration, Some(download)),
+        bps_to_string(download),
+        seconds_to_string(latency),
+    );
+    sender.send(Bytes::from(summary)).await;
+    state.finish(id).await;
+}
+
+pub(crate) fn upload_url(headers: &HeaderMap, id: Uuid) -> String {
+    let scheme = headers
+        .get("X-Forwarded-Proto")
+        .and_then(|proto| proto.to_str().ok())
+        .unwrap_or("http");
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost");
+    format!("{scheme}://{host}/{id}/upload.bin")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terminal_clients() {
+        let cases = [
+            (Some("curl/8.5.0"), None, true),
+            (Some("curl/8.5.0"), Some("*/*"), true),
+            (Some("Wget/1.21.4"), None, true),
+            (
+                Some("HTTPie/3.2.2"),
+                Some("application/json, */*;q=0.5"),
+                true,
+            ),
+            (Some("xh/0.22.0"), None, true),
+            (
+                Some("Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0"),
+                Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+                false,
+            ),
+            (
+                Some(
+                    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Safari/537.36",
+                ),
+                Some("text/html,*/*;q=0.8"),
+                false,
+            ),
+            (Some("Mozilla/5.0 curl/8.5.0"), None, false),
+            (Some("python-requests/2.32"), Some("text/plain"), true),
+            (None, Some("text/plain"), true),
+            (None, Some("text/plain, text/html"), false),
+            (None, Some("*/*"), false),
+            (None, None, false),
+        ];
+        for (user_agent, accept, expected) in cases {
+            let mut headers = HeaderMap::new();
+            if let Some(user_agent) = user_agent {
+                headers.insert(header::USER_AGENT, user_agent.parse().unwrap());
+            }
+            if let Some(accept) = accept {
+                headers.insert(header::ACCEPT, accept.parse().unwrap());
+            }
+            assert_eq!(
+                is_terminal_client(&headers),
+                expected,
+                "{user_agent:?} {accept:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn progress_line_width() {
+        let duration = Duration::from_secs(10);
+        let cases = [
+            (0, None, 0),
+            (1_000, Some(0.0), 3),
+            (5_000, Some(123_456_789.0), 15),
+            (9_999, Some(999_999_999_999.0), 29),
+            (10_000, Some(12.5), 30),
+            (60_000, Some(1e20), 30),
+        ];
+        for (elapsed, speed, filled) in cases {
+            let line = progress_line(Duration::from_millis(elapsed), duration, speed);
+            // Every line overwrites the previous one completely.
+            assert_eq!(line.chars().count(), 54, "{line:?}");
+            assert!(line.starts_with("\r["), "{line:?}");
+            assert_eq!(line.matches('#').count(), filled, "{line:?}");
+        }
+        assert_eq!(
+            progress_line(Duration::from_secs(5), duration, Some(123_456_789.0)),
+            "\r[###############               ]  50%  123 Mbps      "
+        );
+        assert_eq!(
+            progress_line(Duration::ZERO, duration, None),
+            format!("\r[{}]   0%  --            ", " ".repeat(30))
+        );
+    }
+}
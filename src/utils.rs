@@ -1,4 +1,68 @@
-use std::time::Duration;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use axum::http::{HeaderMap, HeaderName, header};
+
+pub(crate) fn client_ip(headers: &HeaderMap, addr: SocketAddr) -> IpAddr {
+    if let Some(ip) = headers.get("X-Forwarded-For")
+        && let Ok(ip_str) = ip.to_str()
+        && let Some(first_ip_str) = ip_str.split(',').next()
+        && let Ok(ip) = first_ip_str.trim().parse()
+    {
+        ip
+    } else {
+        addr.ip().to_canonical()
+    }
+}
+
+/// Headers keeping caches and transforming proxies away from measurement traffic.
+pub(crate) fn measurement_headers() -> [(HeaderName, &'static str); 1] {
+    [(header::CACHE_CONTROL, "no-store, no-transform")]
+}
+
+/// Whether the request went through a proxy that may cache or recompress the
+/// payload, such as one adding a `Via` header or a data saver.
+pub(crate) fn has_proxy_hints(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::VIA)
+        || headers.contains_key("Chrome-Proxy")
+        || headers
+            .get("Save-Data")
+            .is_some_and(|save_data| save_data.as_bytes().eq_ignore_ascii_case(b"on"))
+}
+
+/// Quality value that the `Accept` header gives `media_type`, from its most
+/// specific matching range, or `None` if no range matches.
+pub(crate) fn accept_quality(headers: &HeaderMap, media_type: &str) -> Option<f32> {
+    let accept = headers.get(header::ACCEPT)?.to_str().ok()?;
+    let (main_type, _) = media_type.split_once('/')?;
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parameters = range.split(';');
+            let range = parameters.next()?.trim();
+            let specificity = if range.eq_ignore_ascii_case(media_type) {
+                2
+            } else if range
+                .strip_suffix("/*")
+                .is_some_and(|range| range.eq_ignore_ascii_case(main_type))
+            {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            let quality = parameters
+                .filter_map(|parameter| parameter.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map_or(Some(1.0), |(_, value)| value.trim().parse().ok())?;
+            Some((specificity, quality))
+        })
+        .max_by_key(|&(specificity, _)| specificity)
+        .map(|(_, quality)| quality)
+}
 
 pub(crate) fn calculate_bps(duration: Duration, size: usize) -> f64 {
     (size as f64 / duration.as_secs_f64()) * 8.0
@@ -47,3 +111,58 @@ pub(crate) fn seconds_to_string(latency: f64) -> String {
         _ => format!("{}ms", latency_ms as u64),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_quality() {
+        let cases = [
+            (None, "application/json", None),
+            (Some("application/json"), "application/json", Some(1.0)),
+            (Some("Application/JSON"), "application/json", Some(1.0)),
+            (Some("application/json;q=0"), "application/json", Some(0.0)),
+            (
+                Some("application/json; Q=0.5"),
+                "application/json",
+                Some(0.5),
+            ),
+            (
+                Some("application/json;charset=utf-8"),
+                "application/json",
+                Some(1.0),
+            ),
+            (Some("application/json;q=x"), "application/json", None),
+            (Some("application/jsonl"), "application/json", None),
+            (
+                Some("text/plain, application/*;q=0.2"),
+                "application/json",
+                Some(0.2),
+            ),
+            (
+                Some("*/*;q=0.1, application/json"),
+                "application/json",
+                Some(1.0),
+            ),
+            (
+                Some("application/json;q=0, */*"),
+                "application/json",
+                Some(0.0),
+            ),
+            (Some("*/*"), "text/plain", Some(1.0)),
+            (Some("text/html"), "text/plain", None),
+        ];
+        for (accept, media_type, expected) in cases {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(header::ACCEPT, accept.parse().unwrap());
+            }
+            assert_eq!(
+                super::accept_quality(&headers, media_type),
+                expected,
+                "{accept:?} for {media_type}"
+            );
+        }
+    }
+}